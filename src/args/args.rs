@@ -1,6 +1,118 @@
-use clap::{Parser, Subcommand};
+use crate::build::Artifact;
+use bfc_rs::parse::optimizer::{level, MAX_LEVEL};
+use bfc_rs::{CellWidth, EofPolicy, IoMode, OptimizerStrategies, TapePolicy};
+use clap::{Parser, Subcommand, ValueEnum};
 use std::path::PathBuf;
 
+/// Mirror one of the library's enums as a flag value, so the library doesn't have to know about
+/// clap. The doc comments are what `--help` shows for each value.
+macro_rules! value_enum {
+    (
+        $(#[$meta:meta])*
+        $name:ident => $library:ident {
+            $($(#[$variant_meta:meta])* $variant:ident,)*
+        }
+    ) => {
+        $(#[$meta])*
+        #[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+        pub enum $name {
+            $($(#[$variant_meta])* $variant,)*
+        }
+
+        impl From<$name> for $library {
+            fn from(value: $name) -> Self {
+                match value {
+                    $($name::$variant => $library::$variant,)*
+                }
+            }
+        }
+
+        impl From<$library> for $name {
+            fn from(value: $library) -> Self {
+                match value {
+                    $($library::$variant => $name::$variant,)*
+                }
+            }
+        }
+    };
+}
+
+value_enum! {
+    /// `--cell-width`
+    CellWidthArg => CellWidth {
+        #[value(name = "8")]
+        Eight,
+        #[value(name = "16")]
+        Sixteen,
+        #[value(name = "32")]
+        ThirtyTwo,
+    }
+}
+
+value_enum! {
+    /// `--tape`
+    TapePolicyArg => TapePolicy {
+        /// Come back around the other end.
+        Wrap,
+        /// Stop the program.
+        Abort,
+        /// Make the tape longer when going off the right, stop the program when going off the left.
+        GrowRight,
+        /// Make the tape longer on whichever end the pointer went off.
+        GrowBoth,
+    }
+}
+
+value_enum! {
+    /// `--eof`
+    EofPolicyArg => EofPolicy {
+        /// Store 0.
+        #[value(name = "0")]
+        Zero,
+        /// Store -1, which wraps around to the biggest value a cell can hold.
+        #[value(name = "-1")]
+        MinusOne,
+        /// Leave the cell alone.
+        Unchanged,
+    }
+}
+
+value_enum! {
+    /// `--io-mode`
+    IoModeArg => IoMode {
+        /// One byte per cell, cut down to its lowest 8 bits on the way out.
+        Bytes,
+        /// One UTF-8 encoded code point per cell. Cells that aren't one print as U+FFFD, and so does
+        /// input that isn't valid UTF-8.
+        Utf8,
+    }
+}
+
+value_enum! {
+    /// `--enable-pass` and `--disable-pass`, and the names `--pass-stats` goes by
+    PassArg => OptimizerStrategies {
+        /// Merge runs of the same instruction
+        Contractions,
+        /// Turn `[-]` into a single clear
+        ClearLoop,
+        /// Remove code that can never do anything
+        DeadCode,
+        /// Remove code at the end of the program with no side effects
+        PureCode,
+        /// Turn copy and multiplication loops into straight-line code
+        MultiplyLoop,
+        /// Address cells relative to the pointer instead of moving it around
+        Offsets,
+        /// Turn `[>]` and `[<]` into a search for the next zero cell
+        ScanLoop,
+        /// Drop writes that `,` overwrites (does nothing with `--eof unchanged`, where `,` can leave
+        /// them be)
+        IoClobber,
+        /// Fuse a clear and the additions after it into a single store
+        SetValue,
+    }
+}
+
 /// bfc is a brainfuck compiler/interpreter
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None, arg_required_else_help = true)]
@@ -14,18 +126,55 @@ pub enum Commands {
     /// Compile program into a binary
     #[clap(alias = "c")]
     Compile {
-        /// Emit IR instead of compiling
-        #[arg(short, long, default_value_t = false)]
+        /// Print the IR instead of compiling
+        #[arg(short, long, default_value_t = false, conflicts_with_all = ["emit", "output"])]
         emit_ir: bool,
 
+        /// What to build, any of them separated by commas or given more than once
+        #[arg(long, value_enum, value_delimiter = ',', default_value = "exe")]
+        emit: Vec<Artifact>,
+
+        /// Where to put what's built, instead of next to us named after the input. With more than
+        /// one `--emit`, each gets its own extension added to this
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+
+        /// Leave the IR, assembly and object file in their temporary directory
+        #[arg(long, default_value_t = false)]
+        keep_temps: bool,
+
         /// Force dynamic linking
         #[arg(short, long, default_value_t = false)]
         dynamic: bool,
 
-        /// Enable debugging symbols
+        /// Enable debugging symbols (and turn optimizations off unless `-O` is given)
         #[arg(short = 'g', long, default_value_t = false)]
         debug: bool,
 
+        /// Stop with the offending line and column when the pointer leaves the tape, same as
+        /// `--tape abort`
+        #[arg(long, default_value_t = false, conflicts_with = "tape")]
+        bounds_check: bool,
+
+        /// Bits per cell
+        #[arg(long, value_enum, default_value = "32")]
+        cell_width: CellWidthArg,
+
+        /// What to do when the pointer goes off the end of the tape
+        #[arg(long, value_enum, default_value = "wrap")]
+        tape: TapePolicyArg,
+
+        /// What `,` stores once the input runs out
+        #[arg(long, value_enum, default_value = "0", allow_hyphen_values = true)]
+        eof: EofPolicyArg,
+
+        /// Whether a cell is a byte or a Unicode code point when reading and writing it
+        #[arg(long, value_enum, default_value = "bytes")]
+        io_mode: IoModeArg,
+
+        #[command(flatten)]
+        optimize: OptimizeArgs,
+
         /// Input file
         #[clap(required = true)]
         rest: PathBuf,
@@ -34,6 +183,71 @@ pub enum Commands {
     /// Interpret program
     #[clap(alias = "i")]
     Interpret {
+        /// Bits per cell
+        #[arg(long, value_enum, default_value = "32")]
+        cell_width: CellWidthArg,
+
+        /// What to do when the pointer goes off the end of the tape
+        #[arg(long, value_enum, default_value = "wrap")]
+        tape: TapePolicyArg,
+
+        /// What `,` stores once the input runs out
+        #[arg(long, value_enum, default_value = "0", allow_hyphen_values = true)]
+        eof: EofPolicyArg,
+
+        /// Whether a cell is a byte or a Unicode code point when reading and writing it
+        #[arg(long, value_enum, default_value = "bytes")]
+        io_mode: IoModeArg,
+
+        /// Compile to native code in memory and run that instead (x86-64 only, everything else
+        /// interprets anyway)
+        #[arg(long, default_value_t = false)]
+        jit: bool,
+
+        /// Stop the program after this many steps (bytecode instructions, so how many depends on
+        /// the optimizer)
+        #[arg(long)]
+        max_steps: Option<u64>,
+
+        /// Stop the program instead of growing the tape past this many cells
+        #[arg(long)]
+        max_tape: Option<usize>,
+
+        /// Stop the program instead of writing more than this many bytes
+        #[arg(long)]
+        max_output: Option<u64>,
+
+        #[command(flatten)]
+        optimize: OptimizeArgs,
+
+        /// Input file
+        #[clap(required = true)]
+        rest: PathBuf,
+    },
+
+    /// Step through a program, stopping at breakpoints and at every `#` in it (optimizations are
+    /// off unless `-O` is given)
+    #[clap(alias = "d")]
+    Debug {
+        /// Bits per cell
+        #[arg(long, value_enum, default_value = "32")]
+        cell_width: CellWidthArg,
+
+        /// What to do when the pointer goes off the end of the tape
+        #[arg(long, value_enum, default_value = "wrap")]
+        tape: TapePolicyArg,
+
+        /// What `,` stores once the input runs out
+        #[arg(long, value_enum, default_value = "0", allow_hyphen_values = true)]
+        eof: EofPolicyArg,
+
+        /// Whether a cell is a byte or a Unicode code point when reading and writing it
+        #[arg(long, value_enum, default_value = "bytes")]
+        io_mode: IoModeArg,
+
+        #[command(flatten)]
+        optimize: OptimizeArgs,
+
         /// Input file
         #[clap(required = true)]
         rest: PathBuf,
@@ -43,3 +257,40 @@ pub enum Commands {
     #[clap(alias = "s")]
     Shell {},
 }
+
+#[derive(clap::Args, Debug)]
+pub struct OptimizeArgs {
+    /// Optimization level, 1 when not given (0 when debugging)
+    #[arg(
+        short = 'O',
+        value_name = "LEVEL",
+        value_parser = clap::value_parser!(u8).range(0..=i64::from(MAX_LEVEL))
+    )]
+    pub opt_level: Option<u8>,
+
+    /// Run a pass on top of the ones from the optimization level
+    #[arg(long, value_enum, value_name = "PASS")]
+    pub enable_pass: Vec<PassArg>,
+
+    /// Skip a pass even if the optimization level would run it
+    #[arg(long, value_enum, value_name = "PASS")]
+    pub disable_pass: Vec<PassArg>,
+
+    /// Print what each optimizer pass did
+    #[arg(long, default_value_t = false)]
+    pub pass_stats: bool,
+}
+
+impl OptimizeArgs {
+    /// The passes to run, going off of `default_level` when there's no `-O`.
+    pub fn strategies(&self, default_level: u8) -> Vec<OptimizerStrategies> {
+        let mut strategies = level(self.opt_level.unwrap_or(default_level));
+        for &pass in &self.enable_pass {
+            if !strategies.contains(&pass.into()) {
+                strategies.push(pass.into());
+            }
+        }
+        strategies.retain(|&pass| !self.disable_pass.contains(&pass.into()));
+        strategies
+    }
+}
@@ -0,0 +1,294 @@
+use std::ffi::OsStr;
+use std::fs::{self, DirBuilder};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bfc_rs::BfcError;
+
+/// What `compile` can leave behind, in the order they get made
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
+pub enum Artifact {
+    /// QBE IR (.ssa)
+    Ir,
+    /// Assembly out of qbe (.s)
+    Asm,
+    /// Object file (.o)
+    Obj,
+    /// Linked binary
+    Exe,
+}
+
+impl Artifact {
+    fn extension(&self) -> Option<&'static str> {
+        match self {
+            Artifact::Ir => Some("ssa"),
+            Artifact::Asm => Some("s"),
+            Artifact::Obj => Some("o"),
+            Artifact::Exe => None,
+        }
+    }
+}
+
+/// A directory of our own to put the IR, assembly and object file in while building, so builds
+/// running next to each other never step on each other's files. It goes away when dropped unless
+/// it's been asked to stay.
+pub struct TempDir {
+    path: PathBuf,
+    keep: bool,
+}
+
+impl TempDir {
+    pub fn new(keep: bool) -> Result<Self, BfcError> {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.subsec_nanos());
+        let mut builder = DirBuilder::new();
+        #[cfg(unix)]
+        std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+        let mut attempt = 0;
+        loop {
+            let path =
+                std::env::temp_dir().join(format!("bfc-rs-{}-{nanos}-{attempt}", process::id()));
+            // Fails if it's already there, so nobody else can have made it for us
+            match builder.create(&path) {
+                Ok(()) => return Ok(TempDir { path, keep }),
+                Err(error) if error.kind() == ErrorKind::AlreadyExists && attempt < 100 => {
+                    attempt += 1;
+                }
+                Err(error) => {
+                    return Err(BfcError::io(format!("Could not create {}", path.display()))(error))
+                }
+            }
+        }
+    }
+
+    pub fn get_path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn keep(&mut self) {
+        self.keep = true;
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        if !self.keep {
+            // Nothing sensible to do about it this late, and it's only a temporary directory
+            let _ = fs::remove_dir_all(&self.path);
+        }
+    }
+}
+
+/// Turning IR into whichever artifacts were asked for.
+pub struct Build<'a> {
+    pub emit: &'a [Artifact],
+    /// Where the artifact goes if there's only one of them. With more, every artifact gets its
+    /// own extension added to it.
+    pub output: Option<&'a Path>,
+    pub keep_temps: bool,
+    pub static_link: bool,
+    pub debug: bool,
+}
+
+impl Build<'_> {
+    /// Build `ir`, which was compiled from `input`.
+    pub fn run(&self, ir: &str, input: &Path) -> Result<(), BfcError> {
+        let mut emit = self.emit.to_vec();
+        emit.sort();
+        emit.dedup();
+        let stem = input.file_stem().unwrap_or(OsStr::new("program"));
+        let mut temps = TempDir::new(self.keep_temps)?;
+        let result = self.in_dir(ir, stem, &emit, temps.get_path());
+        if matches!(result, Err(BfcError::Toolchain { .. })) {
+            temps.keep();
+        } else if self.keep_temps {
+            println!(">> Kept temporary files in {}", temps.get_path().display());
+        }
+        result
+    }
+
+    fn in_dir(
+        &self,
+        ir: &str,
+        stem: &OsStr,
+        emit: &[Artifact],
+        dir: &Path,
+    ) -> Result<(), BfcError> {
+        let temp = |artifact: Artifact| with_extension(&dir.join(stem), artifact);
+        let (ir_path, s_path, o_path, exe_path) = (
+            temp(Artifact::Ir),
+            temp(Artifact::Asm),
+            temp(Artifact::Obj),
+            temp(Artifact::Exe),
+        );
+        let temps = [ir_path.clone(), s_path.clone(), o_path.clone()];
+        fs::write(&ir_path, ir).map_err(BfcError::io(format!(
+            "Could not write {}",
+            ir_path.display()
+        )))?;
+        if emit.iter().any(|artifact| *artifact != Artifact::Ir) {
+            println!(">> Generating assembly...");
+            run_tool(
+                Command::new("qbe").arg("-o").arg(&s_path).arg(&ir_path),
+                &temps,
+            )?;
+        }
+        if emit.contains(&Artifact::Obj) {
+            println!(">> Assembling object file...");
+            run_tool(
+                Command::new("cc")
+                    .arg("-c")
+                    .args(self.debug.then_some("-g"))
+                    .arg(&s_path)
+                    .arg("-o")
+                    .arg(&o_path),
+                &temps,
+            )?;
+        }
+        if emit.contains(&Artifact::Exe) {
+            println!(">> Compiling assembly to final binary...");
+            let mut cc = Command::new("cc");
+            // Dynamic is what cc does anyway
+            if self.static_link {
+                cc.arg("-static");
+            }
+            cc.args(["-flto", if self.debug { "-g" } else { "-s" }])
+                .arg(&s_path)
+                .arg("-o")
+                .arg(&exe_path);
+            run_tool(&mut cc, &temps)?;
+        }
+        for &artifact in emit {
+            let from = temp(artifact);
+            let to = self.destination(artifact, stem, emit.len());
+            fs::copy(&from, &to)
+                .map_err(BfcError::io(format!("Could not write {}", to.display())))?;
+        }
+        Ok(())
+    }
+
+    fn destination(&self, artifact: Artifact, stem: &OsStr, artifacts: usize) -> PathBuf {
+        match self.output {
+            Some(output) if artifacts == 1 => output.to_owned(),
+            Some(output) => with_extension(output, artifact),
+            // Right next to where we're being run from, named after the input
+            None => with_extension(Path::new(stem), artifact),
+        }
+    }
+}
+
+/// Add the artifact's extension on top of whatever `path` already has, so `hello.world` doesn't
+/// become `hello.ssa`.
+fn with_extension(path: &Path, artifact: Artifact) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    if let Some(extension) = artifact.extension() {
+        path.push(".");
+        path.push(extension);
+    }
+    PathBuf::from(path)
+}
+
+/// Run `qbe` or `cc`, letting whatever it has to say go straight to our stdout and stderr. If it
+/// fails, `temps` stay where they are so they can be looked at.
+fn run_tool(command: &mut Command, temps: &[PathBuf]) -> Result<(), BfcError> {
+    let tool = command.get_program().to_string_lossy().into_owned();
+    let status = command.status().map_err(|error| {
+        if error.kind() == ErrorKind::NotFound {
+            BfcError::MissingTool { tool: tool.clone() }
+        } else {
+            BfcError::io(format!("Could not run {tool}"))(error)
+        }
+    })?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(BfcError::Toolchain {
+            tool,
+            status,
+            kept: temps.iter().filter(|path| path.exists()).cloned().collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build<'a>(emit: &'a [Artifact], output: Option<&'a Path>) -> Build<'a> {
+        Build {
+            emit,
+            output,
+            keep_temps: false,
+            static_link: false,
+            debug: false,
+        }
+    }
+
+    #[test]
+    fn temp_dirs() {
+        let first = TempDir::new(false).unwrap();
+        let second = TempDir::new(false).unwrap();
+        assert_ne!(first.get_path(), second.get_path());
+        let path = first.get_path().to_owned();
+        fs::write(path.join("hello.ssa"), "").unwrap();
+        drop(first);
+        assert!(!path.exists());
+        assert!(second.get_path().is_dir());
+
+        let mut kept = TempDir::new(false).unwrap();
+        kept.keep();
+        let path = kept.get_path().to_owned();
+        drop(kept);
+        assert!(path.is_dir());
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn naming() {
+        use Artifact::*;
+        let stem = OsStr::new("hello.world");
+        // Named after the input, extensions and all
+        let only = build(&[Ir], None);
+        assert_eq!(only.destination(Ir, stem, 1), Path::new("hello.world.ssa"));
+        let all = build(&[Ir, Asm, Obj, Exe], None);
+        assert_eq!(all.destination(Asm, stem, 4), Path::new("hello.world.s"));
+        assert_eq!(all.destination(Exe, stem, 4), Path::new("hello.world"));
+        // `-o` is taken as is for one artifact, and as a base for more
+        let output = Path::new("out/hi.bin");
+        let only = build(&[Obj], Some(output));
+        assert_eq!(only.destination(Obj, stem, 1), output);
+        let several = build(&[Ir, Obj, Exe], Some(output));
+        assert_eq!(
+            several.destination(Ir, stem, 3),
+            Path::new("out/hi.bin.ssa")
+        );
+        assert_eq!(several.destination(Obj, stem, 3), Path::new("out/hi.bin.o"));
+        assert_eq!(several.destination(Exe, stem, 3), output);
+    }
+
+    #[test]
+    fn emits_ir() {
+        // Doesn't need qbe or cc
+        let dir = TempDir::new(false).unwrap();
+        let output = dir.get_path().join("out.ssa");
+        build(&[Artifact::Ir], Some(&output))
+            .run("export function w $main() {}", Path::new("hello.b"))
+            .unwrap();
+        assert_eq!(
+            fs::read_to_string(output).unwrap(),
+            "export function w $main() {}"
+        );
+    }
+
+    #[test]
+    fn missing_tools() {
+        let mut command = Command::new("bfc-rs-no-such-tool");
+        match run_tool(&mut command, &[]) {
+            Err(BfcError::MissingTool { tool }) => assert_eq!(tool, "bfc-rs-no-such-tool"),
+            other => panic!("{other:?}"),
+        }
+    }
+}
@@ -0,0 +1,329 @@
+use std::collections::BTreeSet;
+use std::io::{stdout, Write};
+
+use bfc_rs::execute::bytecode::Instruction;
+use bfc_rs::parse::ast::{breakpoints, snippet};
+use bfc_rs::{BfcError, Cell, Session, Status};
+use reedline::{DefaultPrompt, DefaultPromptSegment, Reedline, Signal};
+
+const HELP: &str = "\
+    step [N]          (s) Run the next N instructions, or just the next one
+    next              (n) Same as step, but run a whole loop at once when on its `[`
+    continue          (c) Run until a breakpoint or the end
+    break LINE:COL    (b) Stop at the first instruction at or after LINE:COL
+    delete LINE:COL   (d) Take that breakpoint away again
+    breakpoints           List the breakpoints
+    tape [RADIUS]     (t) Show RADIUS cells either side of the pointer, or 8
+    set [CELL] VALUE      Change a cell, or the one under the pointer
+    pointer CELL      (p) Move the pointer
+    where             (w) Show what runs next
+    quit              (q) Stop debugging
+
+An empty line does the last command again. Input the program reads gets typed in a line at a
+time when it asks for it, and Ctrl-D ends it.";
+
+/// How far running goes, if nothing stops it first.
+enum Until {
+    Steps(u64),
+    /// Until the pc gets to this instruction, just past the loop being stepped over
+    Reaching(usize),
+    Breakpoint,
+}
+
+/// Runs a session a bit at a time as the user asks it to, from the command line.
+pub struct Debugger<'a, C: Cell> {
+    session: Session<C>,
+    source: &'a str,
+    /// Instructions to stop in front of
+    breakpoints: BTreeSet<usize>,
+    editor: Reedline,
+    /// Whatever the program last output didn't end its line, so ours need to start a new one
+    mid_line: bool,
+}
+
+impl<'a, C: Cell> Debugger<'a, C> {
+    /// Debug `session`, which runs `source`. Every `#` in it starts out as a breakpoint.
+    pub fn new(session: Session<C>, source: &'a str) -> Self {
+        let bytecode = session.get_bytecode();
+        let breakpoints = breakpoints(source)
+            .into_iter()
+            .filter_map(|location| bytecode.find(location, source))
+            .collect();
+        Debugger {
+            session,
+            source,
+            breakpoints,
+            editor: Reedline::create(),
+            mid_line: false,
+        }
+    }
+
+    pub fn run(mut self) -> Result<(), BfcError> {
+        println!(
+            ">> {} breakpoint(s) from `#`, `help` says what to do",
+            self.breakpoints.len()
+        );
+        self.show_position();
+        let prompt = DefaultPrompt::new(
+            DefaultPromptSegment::Basic("debug".to_owned()),
+            DefaultPromptSegment::Empty,
+        );
+        let mut last = String::new();
+        loop {
+            let line = match self.editor.read_line(&prompt) {
+                Ok(Signal::Success(line)) if line.trim().is_empty() => last.clone(),
+                Ok(Signal::Success(line)) => line,
+                // Ctrl-C or Ctrl-D
+                Ok(_) => return Ok(()),
+                Err(error) => return Err(BfcError::io("Could not read command")(error)),
+            };
+            if !self.command(&line)? {
+                return Ok(());
+            }
+            last = line;
+        }
+    }
+
+    /// Carry out one line typed in, saying whether to keep going.
+    fn command(&mut self, line: &str) -> Result<bool, BfcError> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words[..] {
+            [] => (),
+            ["step" | "s"] => self.go(Until::Steps(1))?,
+            ["step" | "s", count] => match count.parse() {
+                Ok(count) => self.go(Until::Steps(count))?,
+                Err(_) => println!("`{count}` isn't a number of steps"),
+            },
+            ["next" | "n"] => {
+                let code = self.session.get_bytecode().get_code();
+                let until = match code.get(self.session.get_pc()) {
+                    Some(Instruction::JumpIfZero(after)) => Until::Reaching(*after),
+                    _ => Until::Steps(1),
+                };
+                self.go(until)?;
+            }
+            ["continue" | "c"] => self.go(Until::Breakpoint)?,
+            ["break" | "b", location] => {
+                if let Some(pc) = self.find(location) {
+                    self.breakpoints.insert(pc);
+                    println!("Breakpoint at {}", self.describe(pc));
+                }
+            }
+            ["delete" | "d", location] => {
+                if let Some(pc) = self.find(location) {
+                    if self.breakpoints.remove(&pc) {
+                        println!("Deleted the breakpoint at {}", self.describe(pc));
+                    } else {
+                        println!("There's no breakpoint at {}", self.describe(pc));
+                    }
+                }
+            }
+            ["breakpoints"] => {
+                if self.breakpoints.is_empty() {
+                    println!("No breakpoints");
+                }
+                for &pc in &self.breakpoints {
+                    println!("{}", self.describe(pc));
+                }
+            }
+            ["tape" | "t"] => self.show_tape(8),
+            ["tape" | "t", radius] => match radius.parse() {
+                Ok(radius) => self.show_tape(radius),
+                Err(_) => println!("`{radius}` isn't a number of cells"),
+            },
+            ["set", value] => self.set(None, value),
+            ["set", cell, value] => self.set(Some(cell), value),
+            ["pointer" | "p", cell] => match cell.parse() {
+                Ok(ptr) => {
+                    self.session.get_machine_mut().set_pointer(ptr);
+                    self.show_tape(4);
+                }
+                Err(_) => println!("`{cell}` isn't a cell"),
+            },
+            ["where" | "w"] => self.show_position(),
+            ["help" | "h"] => println!("{HELP}"),
+            ["quit" | "q"] => return Ok(false),
+            _ => println!("Don't know `{}`, try `help`", line.trim()),
+        }
+        Ok(true)
+    }
+
+    /// Run until `until` says to stop, a breakpoint comes up, or the program ends. Errors don't
+    /// end anything: the program stays on the instruction that failed, to be fixed up and run
+    /// again.
+    fn go(&mut self, until: Until) -> Result<(), BfcError> {
+        let mut ran = 0;
+        loop {
+            let status = self.session.resume(Some(1));
+            self.flush_output()?;
+            match status {
+                Ok(Status::Yielded) => ran += 1,
+                Ok(Status::NeedsInput) => {
+                    if self.read_input()? {
+                        continue;
+                    }
+                    break;
+                }
+                Ok(Status::Ended(outcome)) => {
+                    self.end_line();
+                    println!(">> {outcome}");
+                    return Ok(());
+                }
+                Err(error) => {
+                    self.end_line();
+                    println!(">> {error}");
+                    break;
+                }
+            }
+            let pc = self.session.get_pc();
+            if self.breakpoints.contains(&pc) {
+                self.end_line();
+                println!(">> Breakpoint");
+                break;
+            }
+            match until {
+                Until::Steps(steps) if ran >= steps => break,
+                Until::Reaching(after) if pc == after => break,
+                _ => (),
+            }
+        }
+        self.show_position();
+        Ok(())
+    }
+
+    /// Ask for a line of input for the program, saying whether it got one (or the end of it)
+    /// rather than being cancelled.
+    fn read_input(&mut self) -> Result<bool, BfcError> {
+        self.end_line();
+        let prompt = DefaultPrompt::new(
+            DefaultPromptSegment::Basic("input".to_owned()),
+            DefaultPromptSegment::Empty,
+        );
+        match self.editor.read_line(&prompt) {
+            Ok(Signal::Success(line)) => {
+                self.session.feed(line.as_bytes());
+                self.session.feed(b"\n");
+                Ok(true)
+            }
+            Ok(Signal::CtrlD) => {
+                self.session.close_input();
+                Ok(true)
+            }
+            // Still waiting on the `,`, for whenever the user carries on
+            Ok(_) => Ok(false),
+            Err(error) => Err(BfcError::io("Could not read input")(error)),
+        }
+    }
+
+    fn flush_output(&mut self) -> Result<(), BfcError> {
+        let output = self.session.take_output();
+        if let Some(&last) = output.last() {
+            self.mid_line = last != b'\n';
+            let mut stdout = stdout();
+            stdout
+                .write_all(&output)
+                .and_then(|()| stdout.flush())
+                .map_err(BfcError::io("Could not write output"))?;
+        }
+        Ok(())
+    }
+
+    fn end_line(&mut self) {
+        if self.mid_line {
+            println!();
+            self.mid_line = false;
+        }
+    }
+
+    /// The instruction a `LINE:COL` typed in stops at.
+    fn find(&self, location: &str) -> Option<usize> {
+        let parsed = location
+            .split_once(':')
+            .and_then(|(line, column)| Some((line.parse().ok()?, column.parse().ok()?)));
+        let Some(location) = parsed else {
+            println!("Breakpoints go at LINE:COL, like 3:14");
+            return None;
+        };
+        let pc = self.session.get_bytecode().find(location, self.source);
+        if pc.is_none() {
+            println!(
+                "Nothing runs at or after line {}, column {}",
+                location.0, location.1
+            );
+        }
+        pc
+    }
+
+    fn describe(&self, pc: usize) -> String {
+        let (line, column) = self.session.get_bytecode().get_location(pc);
+        format!("line {line}, column {column}")
+    }
+
+    fn show_position(&mut self) {
+        self.end_line();
+        let pc = self.session.get_pc();
+        match self.session.get_location() {
+            Some(location) => {
+                let instruction = self.session.get_bytecode().get_code()[pc];
+                println!(">> Next up is {instruction:?} at {}", self.describe(pc));
+                println!("{}", snippet(self.source, location));
+            }
+            None => println!(">> At the end of the program"),
+        }
+        self.show_tape(4);
+    }
+
+    /// The cells `radius` either side of the pointer, and what's in the one under it.
+    fn show_tape(&self, radius: usize) {
+        let machine = self.session.get_machine();
+        let tape = machine.get_tape();
+        let ptr = machine.get_pointer();
+        let Some(at) = usize::try_from(ptr).ok().filter(|&at| at < tape.len()) else {
+            println!("The pointer's at {ptr}, off the {} cell tape", tape.len());
+            return;
+        };
+        let (mut cells, mut values, mut caret) = (String::new(), String::new(), String::new());
+        for (index, cell) in tape
+            .iter()
+            .enumerate()
+            .take(at.saturating_add(radius).saturating_add(1))
+            .skip(at.saturating_sub(radius))
+        {
+            let value = cell.to_u32().to_string();
+            let width = index.to_string().len().max(value.len()) + 1;
+            cells += &format!("{index:>width$}");
+            values += &format!("{value:>width$}");
+            caret += &format!("{:>width$}", if index == at { "^" } else { "" });
+        }
+        println!("cell {cells}\nvalue{values}\n     {caret}");
+        let value = tape[at].to_u32();
+        match char::from_u32(value).filter(|c| !c.is_control()) {
+            Some(c) => println!("Cell {at} holds {value} ({c:?})"),
+            None => println!("Cell {at} holds {value}"),
+        }
+    }
+
+    /// Put `value` in `cell`, or the one under the pointer.
+    fn set(&mut self, cell: Option<&str>, value: &str) {
+        let machine = self.session.get_machine_mut();
+        let at = match cell {
+            Some(cell) => cell.parse().ok(),
+            None => usize::try_from(machine.get_pointer()).ok(),
+        };
+        let Ok(value) = value.parse::<i64>() else {
+            println!("`{value}` isn't a number");
+            return;
+        };
+        let tape = machine.get_tape_mut();
+        let len = tape.len();
+        match at.and_then(|at| tape.get_mut(at)) {
+            // Wraps around, so -1 is the biggest a cell can hold
+            Some(cell) => *cell = C::from_u32(value as u32),
+            None => {
+                println!("That's not a cell on the {len} cell tape");
+                return;
+            }
+        }
+        self.show_tape(4);
+    }
+}
@@ -0,0 +1,142 @@
+use crate::execute::interpret::ExecOutcome;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::process::ExitStatus;
+
+/// Everything that can go wrong between reading a brainfuck file and the program finishing.
+#[derive(Debug)]
+pub enum BfcError {
+    /// Brackets that never got matched, as (bracket, (line, column)), along with the report
+    /// pointing each of them out in the source. Loops nested deeper than `ast::MAX_NESTING` are
+    /// reported the same way, with nothing `unmatched`.
+    Parse {
+        unmatched: Vec<(char, (u32, u32))>,
+        report: String,
+    },
+    /// Reading or writing something failed, be it the source file, a temporary file, or the
+    /// program's own input and output.
+    Io { context: String, source: io::Error },
+    /// `qbe` or `cc` isn't anywhere on `PATH`.
+    MissingTool { tool: String },
+    /// `qbe` or `cc` ran but failed (and already said why on stderr). The files it was working on
+    /// are `kept` around to look at.
+    Toolchain {
+        tool: String,
+        status: ExitStatus,
+        kept: Vec<PathBuf>,
+    },
+    /// The pointer left the tape and the tape policy said to stop.
+    OffTape { line: u32, column: u32 },
+    /// The program hit one of its `Limits`, or was caught looping forever. Running hands this
+    /// back as an `ExecOutcome`, which isn't an error to the library, but the command line still
+    /// wants an exit code for it.
+    Exhausted(ExecOutcome),
+    /// The options don't make sense together, like a tape without any cells.
+    Options(String),
+}
+
+impl BfcError {
+    /// The process exit code for this error, following sysexits.h.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            BfcError::Options(_) => 64,
+            BfcError::Parse { .. } => 65,
+            BfcError::MissingTool { .. } => 69,
+            BfcError::OffTape { .. } => 70,
+            BfcError::Toolchain { .. } => 73,
+            BfcError::Io { .. } => 74,
+            BfcError::Exhausted(_) => 75,
+        }
+    }
+
+    /// For `map_err`, to say what was being done when an I/O error happened.
+    pub fn io(context: impl Into<String>) -> impl FnOnce(io::Error) -> BfcError {
+        let context = context.into();
+        move |source| BfcError::Io { context, source }
+    }
+}
+
+impl fmt::Display for BfcError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BfcError::Parse { report, .. } => write!(fmt, "{report}"),
+            BfcError::Io { context, source } => write!(fmt, "{context}: {source}"),
+            BfcError::MissingTool { tool } => {
+                write!(
+                    fmt,
+                    "Could not find {tool}, is it installed and on your PATH?"
+                )
+            }
+            BfcError::Toolchain { tool, status, kept } => {
+                write!(fmt, "{tool} failed ({status})")?;
+                if !kept.is_empty() {
+                    let kept: Vec<_> = kept.iter().map(|path| path.display().to_string()).collect();
+                    write!(fmt, ", kept {} for a closer look", kept.join(" and "))?;
+                }
+                Ok(())
+            }
+            BfcError::OffTape { line, column } => {
+                write!(fmt, "Pointer left the tape at line {line}, column {column}")
+            }
+            BfcError::Exhausted(outcome) => write!(fmt, "{outcome}"),
+            BfcError::Options(reason) => write!(fmt, "{reason}"),
+        }
+    }
+}
+
+impl std::error::Error for BfcError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BfcError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exit_codes() {
+        let errors = [
+            (BfcError::Options("no cells".to_owned()), 64),
+            (
+                BfcError::Parse {
+                    unmatched: vec![('[', (1, 1))],
+                    report: String::new(),
+                },
+                65,
+            ),
+            (
+                BfcError::MissingTool {
+                    tool: "qbe".to_owned(),
+                },
+                69,
+            ),
+            (BfcError::OffTape { line: 1, column: 2 }, 70),
+            (
+                BfcError::io("Could not read file")(io::ErrorKind::NotFound.into()),
+                74,
+            ),
+            (
+                BfcError::Exhausted(ExecOutcome::Endless { location: (1, 1) }),
+                75,
+            ),
+        ];
+        for (error, code) in errors {
+            assert_eq!(error.exit_code(), code, "{error:?}");
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::ExitStatusExt;
+            let failed = BfcError::Toolchain {
+                tool: "cc".to_owned(),
+                status: ExitStatus::from_raw(1 << 8),
+                kept: vec![],
+            };
+            assert_eq!(failed.exit_code(), 73);
+        }
+    }
+}
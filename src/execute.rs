@@ -1,3 +1,6 @@
+pub mod bytecode;
 pub mod compile;
 pub mod interpret;
+pub mod jit;
 pub mod machine;
+pub mod session;
@@ -0,0 +1,240 @@
+use crate::parse::ast::closing_loop;
+use crate::parse::opcodes::{OpCodes, Tokens};
+
+/// One step of a flattened program. Loops become a pair of jumps that already know where they
+/// go, so running them never has to look for the matching bracket or recurse into a body.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    Add(u32, i32),
+    Sub(u32, i32),
+    Inc(u32),
+    Dec(u32),
+    Output(i32),
+    Input(i32),
+    Clear(i32),
+    Set(u32, i32),
+    /// Index of the (offset, factor) targets in `Bytecode::get_multiplies`
+    MulAdd(usize),
+    Scan(i32),
+    /// `[`: go to the instruction given, just past the matching `]`, when the current cell is 0.
+    JumpIfZero(usize),
+    /// `]`: go back to the instruction given, just past the matching `[`, unless the current
+    /// cell is 0.
+    JumpUnlessZero(usize),
+    /// `>]` or `<]`: move the pointer first, then the same as `JumpUnlessZero`. Loops that walk
+    /// the tape end like this all the time, and it saves going around the dispatch loop twice.
+    MoveJumpUnlessZero(isize, usize),
+}
+
+/// An optimized AST lowered to a flat array of instructions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bytecode {
+    code: Vec<Instruction>,
+    multiplies: Vec<Vec<(i32, i32)>>,
+    /// line, column of the token every instruction came from, kept out of `code` so it stays
+    /// small
+    locations: Vec<(u32, u32)>,
+}
+
+impl Bytecode {
+    /// Flatten `ast`, however deeply its loops are nested, without recursing.
+    pub fn lower(ast: &[Tokens]) -> Self {
+        let mut bytecode = Bytecode {
+            code: Vec::new(),
+            multiplies: Vec::new(),
+            locations: Vec::new(),
+        };
+        // What's left of every body we're inside of, and where the `[` opening it is
+        let mut bodies = vec![(ast.iter(), None)];
+        while let Some((body, open)) = bodies.last_mut() {
+            let Some(token) = body.next() else {
+                if let Some(open) = *open {
+                    let by = match bytecode.code[open + 1..] {
+                        [.., Instruction::Inc(x)] => x as isize,
+                        [.., Instruction::Dec(x)] => -(x as isize),
+                        _ => 0,
+                    };
+                    let close = if by == 0 {
+                        bytecode.code.len()
+                    } else {
+                        // Moving never stops the machine, so nothing needs its location
+                        bytecode.code.pop();
+                        bytecode.locations.pop();
+                        bytecode.code.len()
+                    };
+                    bytecode.code[open] = Instruction::JumpIfZero(close + 1);
+                    bytecode.push(
+                        if by == 0 {
+                            Instruction::JumpUnlessZero(open + 1)
+                        } else {
+                            Instruction::MoveJumpUnlessZero(by, open + 1)
+                        },
+                        bytecode.locations[open],
+                    );
+                }
+                bodies.pop();
+                continue;
+            };
+            let instruction = match token.get_type() {
+                OpCodes::Add(x, offset) => Instruction::Add(*x, *offset),
+                OpCodes::Sub(x, offset) => Instruction::Sub(*x, *offset),
+                OpCodes::Inc(x) => Instruction::Inc(*x),
+                OpCodes::Dec(x) => Instruction::Dec(*x),
+                OpCodes::Output(offset) => Instruction::Output(*offset),
+                OpCodes::Input(offset) => Instruction::Input(*offset),
+                OpCodes::Clear(offset) => Instruction::Clear(*offset),
+                OpCodes::Set(x, offset) => Instruction::Set(*x, *offset),
+                OpCodes::MulAdd(targets) => {
+                    bytecode.multiplies.push(targets.clone());
+                    Instruction::MulAdd(bytecode.multiplies.len() - 1)
+                }
+                OpCodes::Scan(stride) => Instruction::Scan(*stride),
+                OpCodes::Loop(inner) => {
+                    bodies.push((inner.iter(), Some(bytecode.code.len())));
+                    // Pointed past the `]` once it's been lowered
+                    Instruction::JumpIfZero(0)
+                }
+            };
+            bytecode.push(instruction, token.get_location());
+        }
+        bytecode
+    }
+
+    fn push(&mut self, instruction: Instruction, location: (u32, u32)) {
+        self.code.push(instruction);
+        self.locations.push(location);
+    }
+
+    pub fn get_code(&self) -> &[Instruction] {
+        &self.code
+    }
+
+    pub fn get_multiplies(&self, index: usize) -> &[(i32, i32)] {
+        &self.multiplies[index]
+    }
+
+    /// line, column of the token instruction `pc` came from. Both jumps of a loop point at its
+    /// `[`, even when the `]` took a move along with it.
+    pub fn get_location(&self, pc: usize) -> (u32, u32) {
+        self.locations[pc]
+    }
+
+    /// The instruction that runs first out of everything at or after `location` in `source`, for
+    /// stopping at a place that might be a comment or got merged into something before it.
+    /// Loops count as where their `[` is, and the end of a loop's body as the jump back at its
+    /// `]`, rather than whatever comes after the loop.
+    pub fn find(&self, location: (u32, u32), source: &str) -> Option<usize> {
+        let after = (0..self.code.len())
+            .filter(|&pc| self.locations[pc] >= location)
+            .min_by_key(|&pc| (self.locations[pc], pc));
+        let jump = closing_loop(source, location).and_then(|open| {
+            (0..self.code.len()).find(|&pc| {
+                self.locations[pc] == open
+                    && matches!(
+                        self.code[pc],
+                        Instruction::JumpUnlessZero(_) | Instruction::MoveJumpUnlessZero(..)
+                    )
+            })
+        });
+        // Anything left in the body runs before the jump
+        after.into_iter().chain(jump).min()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::ast::generate_ast;
+
+    fn lower(program: &str) -> Vec<Instruction> {
+        Bytecode::lower(&generate_ast(&mut program.chars()).unwrap()).code
+    }
+
+    #[test]
+    fn jump_targets() {
+        use Instruction::*;
+        assert_eq!(
+            lower("+[>[-.]<-]."),
+            [
+                Add(1, 0),
+                JumpIfZero(10),
+                Inc(1),
+                JumpIfZero(7),
+                Sub(1, 0),
+                Output(0),
+                JumpUnlessZero(4),
+                Dec(1),
+                Sub(1, 0),
+                JumpUnlessZero(2),
+                Output(0),
+            ]
+        );
+        // The move before `]` rides along with the jump
+        assert_eq!(
+            lower("+[->]<"),
+            [
+                Add(1, 0),
+                JumpIfZero(4),
+                Sub(1, 0),
+                MoveJumpUnlessZero(1, 2),
+                Dec(1)
+            ]
+        );
+        assert_eq!(
+            lower("+[-<]"),
+            [
+                Add(1, 0),
+                JumpIfZero(4),
+                Sub(1, 0),
+                MoveJumpUnlessZero(-1, 2)
+            ]
+        );
+        assert_eq!(
+            lower("[][]"),
+            [
+                JumpIfZero(2),
+                JumpUnlessZero(1),
+                JumpIfZero(4),
+                JumpUnlessZero(3)
+            ]
+        );
+    }
+
+    #[test]
+    fn locations() {
+        let source = "+\n [.]";
+        let bytecode = Bytecode::lower(&generate_ast(&mut source.chars()).unwrap());
+        assert_eq!(bytecode.get_location(0), (1, 1));
+        assert_eq!(bytecode.get_location(1), (2, 2));
+        assert_eq!(bytecode.get_location(2), (2, 3));
+        assert_eq!(bytecode.get_location(3), (2, 2));
+        assert_eq!(bytecode.find((1, 1), source), Some(0));
+        // The `[` rather than the `]` jumping back to it
+        assert_eq!(bytecode.find((1, 2), source), Some(1));
+        assert_eq!(bytecode.find((2, 3), source), Some(2));
+        // Until after the body
+        assert_eq!(bytecode.find((2, 4), source), Some(3));
+        assert_eq!(bytecode.find((2, 5), source), None);
+    }
+
+    #[test]
+    fn loop_ends() {
+        use Instruction::*;
+        let find = |source: &str, location| {
+            let bytecode = Bytecode::lower(&generate_ast(&mut source.chars()).unwrap());
+            let pc = bytecode.find(location, source)?;
+            Some(bytecode.get_code()[pc])
+        };
+        // At the `#`, which is the `]` going back round rather than the `.` after the loop
+        assert_eq!(find("+++[>+<-#].", (1, 9)), Some(JumpUnlessZero(4)));
+        // Same when the `]` took the move before it along
+        assert_eq!(
+            find("+++[>+<-#<].", (1, 9)),
+            Some(MoveJumpUnlessZero(-1, 4))
+        );
+        assert_eq!(find("+++[>+#<-].", (1, 7)), Some(Dec(1)));
+        // Past the `]` is past the loop
+        assert_eq!(find("+++[>+<-]#.", (1, 10)), Some(Output(0)));
+        assert_eq!(find("+[[-]#]", (1, 6)), Some(JumpUnlessZero(2)));
+    }
+}
@@ -1,11 +1,34 @@
-use crate::execute::machine::Machine;
+use crate::execute::machine::{CellWidth, EofPolicy, IoMode, TapePolicy};
 use crate::parse::opcodes::{OpCodes, Tokens};
+use crate::Options;
 use qbe::*;
+use std::collections::BTreeSet;
 
-/// Return our QBE IR, and also a bool if this should be statically compiled
+/// Everything about the tape that code generation needs to know.
+struct Tape {
+    width: CellWidth,
+    policy: TapePolicy,
+    /// How many cells the tape starts out with, which is all it will ever have when wrapping.
+    cells: u64,
+    /// Every source location that checks the pointer, so each one can get its own error message.
+    checks: BTreeSet<(u32, u32)>,
+    /// What `,` does once `getchar` runs out.
+    eof: EofPolicy,
+    /// Whether `,` and `.` go through `getchar`/`putchar` directly or through the UTF-8 runtime.
+    io: IoMode,
+}
+
+impl Tape {
+    fn bytes(&self) -> u64 {
+        self.cells * self.width.bytes()
+    }
+}
+
+/// Return our QBE IR, and also a bool if this should be statically compiled. Only the tape and
+/// I/O parts of `options` make a difference.
 pub fn compile(
     ast: &Vec<Tokens>,
-    machine: &Machine,
+    options: &Options,
     debug: bool,
     file_location: &str,
 ) -> (String, bool) {
@@ -29,12 +52,48 @@ pub fn compile(
     let mut module = Module::new();
     let mut counter = 1;
     let mut while_counter = 1;
+    let mut tape = Tape {
+        width: options.cell_width,
+        policy: options.tape,
+        cells: options.tape_size as u64,
+        checks: BTreeSet::new(),
+        eof: options.eof,
+        io: options.io_mode,
+    };
+    // The tape lives on the heap so `$bfc_tape_fix` can grow it, which means keeping track of
+    // where it starts and how many bytes (not cells) it has
     module.add_data(DataDef {
         linkage: Linkage::private(),
-        name: "tape".into(),
+        name: "tape_start".into(),
         align: Some(8),
-        items: vec![(Type::Zero, DataItem::Const(machine.get_size() as u64))],
+        items: vec![(Type::Long, DataItem::Const(0))],
+    });
+    module.add_data(DataDef {
+        linkage: Linkage::private(),
+        name: "tape_bytes".into(),
+        align: Some(8),
+        items: vec![(Type::Long, DataItem::Const(tape.bytes()))],
+    });
+    module.add_data(DataDef {
+        linkage: Linkage::private(),
+        name: "tape_message".into(),
+        align: None,
+        items: vec![
+            (
+                Type::Byte,
+                DataItem::Str("Could not allocate the tape".to_owned()),
+            ),
+            (Type::Byte, DataItem::Const(u64::from(b'\n'))),
+            (Type::Byte, DataItem::Const(0)),
+        ],
     });
+    if tape.policy != TapePolicy::Wrap {
+        module.add_function(tape_fix(tape.policy));
+    }
+    if tape.io == IoMode::Utf8 {
+        module.add_function(put_utf8());
+        module.add_function(get_utf8());
+    }
     // Create `main`
     if debug {
         output_string.push_str(&format!(r#"dbgfile "{}""#, file_location));
@@ -54,28 +113,84 @@ pub fn compile(
         Instr::Alloc8(8),
     );
 
-    // storel $tape, %.1
+    // %.2 =l call $calloc(l cells, l cell size)
+    // %.3 =w cnel %.2, 0
+    // jnz %.3, @tape, @no_tape
+    // @no_tape
+    //     %.4 =l loadl $stderr
+    //     call $fputs(l $tape_message, l %.4)
+    //     ret 1
+    // @tape
+    //     storel %.2, $tape_start
+    //     storel %.2, %.1
+    func.assign_instr(
+        Value::Temporary(format_counter(counter + 1)),
+        Type::Long,
+        Instr::Call(
+            "calloc".to_owned(),
+            vec![
+                (Type::Long, Value::Const(tape.cells)),
+                (Type::Long, Value::Const(tape.width.bytes())),
+            ],
+        ),
+    );
+    func.assign_instr(
+        Value::Temporary(format_counter(counter + 2)),
+        Type::Word,
+        Instr::Cmp(
+            Type::Long,
+            Cmp::Ne,
+            Value::Temporary(format_counter(counter + 1)),
+            Value::Const(0),
+        ),
+    );
+    func.add_instr(Instr::Jnz(
+        Value::Temporary(format_counter(counter + 2)),
+        "tape".to_owned(),
+        "no_tape".to_owned(),
+    ));
+    func.add_block("no_tape".to_owned());
+    func.assign_instr(
+        Value::Temporary(format_counter(counter + 3)),
+        Type::Long,
+        Instr::Load(Type::Long, Value::Global("stderr".to_owned())),
+    );
+    func.add_instr(Instr::Call(
+        "fputs".to_owned(),
+        vec![
+            (Type::Long, Value::Global("tape_message".to_owned())),
+            (Type::Long, Value::Temporary(format_counter(counter + 3))),
+        ],
+    ));
+    func.add_instr(Instr::Ret(Some(Value::Const(1))));
+    func.add_block("tape".to_owned());
+    func.add_instr(Instr::Store(
+        Type::Long,
+        Value::Global("tape_start".to_owned()),
+        Value::Temporary(format_counter(counter + 1)),
+    ));
     func.add_instr(Instr::Store(
         Type::Long,
         Value::Temporary(format_counter(counter)),
-        Value::Global("tape".to_owned()),
+        Value::Temporary(format_counter(counter + 1)),
     ));
+    counter += 3;
 
-    // %.2 =l loadl $stdout
+    // %.5 =l loadl $stdout
     func.assign_instr(
         Value::Temporary(format_counter(counter + 1)),
         Type::Long,
         Instr::Load(Type::Long, Value::Global("stdout".to_owned())),
     );
 
-    // %.3 =l extsw 0
+    // %.6 =l extsw 0
     func.assign_instr(
         Value::Temporary(format_counter(counter + 2)),
         Type::Long,
         Instr::Exts(Type::Word, Value::Const(0)),
     );
 
-    // call $setbuf(l %.2, l %.3)
+    // call $setbuf(l %.5, l %.6)
     // The goal of this is to disable buffering, because it will give some programs that print a
     // lot in a single line but takes a while a visible speed boost so that the user can see
     // something is going on.
@@ -89,9 +204,33 @@ pub fn compile(
 
     counter += 2;
 
-    generate_qbe(ast, &mut counter, &mut while_counter, debug, &mut func);
+    generate_qbe(
+        ast,
+        &mut counter,
+        &mut while_counter,
+        &mut tape,
+        debug,
+        &mut func,
+    );
     func.add_instr(Instr::Ret(Some(Value::Const(0))));
     module.add_function(func);
+    for (line, column) in tape.checks {
+        module.add_data(DataDef {
+            linkage: Linkage::private(),
+            name: format_bounds_message(line, column),
+            align: None,
+            items: vec![
+                (
+                    Type::Byte,
+                    DataItem::Str(format!(
+                        "Pointer left the tape at line {line}, column {column}"
+                    )),
+                ),
+                (Type::Byte, DataItem::Const(u64::from(b'\n'))),
+                (Type::Byte, DataItem::Const(0)),
+            ],
+        });
+    }
     (
         if !output_string.is_empty() {
             output_string + "\n"
@@ -110,10 +249,764 @@ fn format_label(value: i32) -> String {
     format!("loop.{value}")
 }
 
+fn format_bounds_message(line: u32, column: u32) -> String {
+    format!("bounds.{line}.{column}")
+}
+
+/// How a cell is loaded: zero extended into a word, so wrapping works the same for every width.
+fn load_type(width: CellWidth) -> Type<'static> {
+    match width {
+        CellWidth::Eight => Type::UnsignedByte,
+        CellWidth::Sixteen => Type::UnsignedHalfword,
+        CellWidth::ThirtyTwo => Type::Word,
+    }
+}
+
+/// How a cell is stored, which is also where the value gets cut down to the cell's width.
+fn store_type(width: CellWidth) -> Type<'static> {
+    match width {
+        CellWidth::Eight => Type::Byte,
+        CellWidth::Sixteen => Type::Halfword,
+        CellWidth::ThirtyTwo => Type::Word,
+    }
+}
+
+/// Load the pointer and move it `offset` cells over, returning the temporary holding the address.
+///
+/// %.2 =l loadl %.1
+/// %.3 =l add %.2, offset*cell size
+///
+/// When wrapping the pointer is always on the tape, so the offset is turned into one forwards of
+/// less than a whole tape and `wrap_address` brings it back around. Otherwise the address gets
+/// checked against the tape, see `check_tape`.
+fn cell_address(
+    offset: i32,
+    location: (u32, u32),
+    tape: &mut Tape,
+    counter: &mut i32,
+    func: &mut Function<'_>,
+) -> Value {
+    let wraps = tape.policy == TapePolicy::Wrap;
+    let offset = if wraps {
+        i64::from(offset).rem_euclid(tape.cells as i64)
+    } else {
+        i64::from(offset)
+    };
+    let retry = format!("tape.{}", *counter + 1);
+    if !wraps {
+        func.add_block(retry.clone());
+    }
+    func.assign_instr(
+        Value::Temporary(format_counter(*counter + 1)),
+        Type::Long,
+        Instr::Load(Type::Long, Value::Temporary(format_counter(1))),
+    );
+    *counter += 1;
+    if offset == 0 {
+        let address = Value::Temporary(format_counter(*counter));
+        if !wraps {
+            check_tape(&address, location, retry, tape, counter, func);
+        }
+        return address;
+    }
+    let distance = Value::Const(offset.unsigned_abs() * tape.width.bytes());
+    func.assign_instr(
+        Value::Temporary(format_counter(*counter + 1)),
+        Type::Long,
+        if offset < 0 {
+            Instr::Sub(Value::Temporary(format_counter(*counter)), distance)
+        } else {
+            Instr::Add(Value::Temporary(format_counter(*counter)), distance)
+        },
+    );
+    *counter += 1;
+    let address = Value::Temporary(format_counter(*counter));
+    if wraps {
+        wrap_address(address, tape, counter, func)
+    } else {
+        check_tape(&address, location, retry, tape, counter, func);
+        address
+    }
+}
+
+/// Bring an address that's less than a whole tape past the end of it back around to the start,
+/// without branching:
+///
+/// %.4 =l loadl $tape_start
+/// %.5 =l sub %.3, %.4
+/// %.6 =l csgel %.5, tape size
+/// %.7 =l mul %.6, tape size
+/// %.8 =l sub %.3, %.7
+fn wrap_address(address: Value, tape: &Tape, counter: &mut i32, func: &mut Function<'_>) -> Value {
+    func.assign_instr(
+        Value::Temporary(format_counter(*counter + 1)),
+        Type::Long,
+        Instr::Load(Type::Long, Value::Global("tape_start".to_owned())),
+    );
+    func.assign_instr(
+        Value::Temporary(format_counter(*counter + 2)),
+        Type::Long,
+        Instr::Sub(
+            address.clone(),
+            Value::Temporary(format_counter(*counter + 1)),
+        ),
+    );
+    func.assign_instr(
+        Value::Temporary(format_counter(*counter + 3)),
+        Type::Long,
+        Instr::Cmp(
+            Type::Long,
+            Cmp::Sge,
+            Value::Temporary(format_counter(*counter + 2)),
+            Value::Const(tape.bytes()),
+        ),
+    );
+    func.assign_instr(
+        Value::Temporary(format_counter(*counter + 4)),
+        Type::Long,
+        Instr::Mul(
+            Value::Temporary(format_counter(*counter + 3)),
+            Value::Const(tape.bytes()),
+        ),
+    );
+    func.assign_instr(
+        Value::Temporary(format_counter(*counter + 5)),
+        Type::Long,
+        Instr::Sub(address, Value::Temporary(format_counter(*counter + 4))),
+    );
+    *counter += 5;
+    Value::Temporary(format_counter(*counter))
+}
+
+/// Move the pointer by `by` cells, the same way `cell_address` works out an offset.
+///
+/// %.2 =l loadl %.1
+/// %.3 =l add %.2, by*cell size
+/// storel %.3, %.1
+fn move_pointer(by: i64, tape: &Tape, counter: &mut i32, func: &mut Function<'_>) {
+    let wraps = tape.policy == TapePolicy::Wrap;
+    let by = if wraps {
+        by.rem_euclid(tape.cells as i64)
+    } else {
+        by
+    };
+    func.assign_instr(
+        Value::Temporary(format_counter(*counter + 1)),
+        Type::Long,
+        Instr::Load(Type::Long, Value::Temporary(format_counter(1))),
+    );
+    let distance = Value::Const(by.unsigned_abs() * tape.width.bytes());
+    func.assign_instr(
+        Value::Temporary(format_counter(*counter + 2)),
+        Type::Long,
+        if by < 0 {
+            Instr::Sub(Value::Temporary(format_counter(*counter + 1)), distance)
+        } else {
+            Instr::Add(Value::Temporary(format_counter(*counter + 1)), distance)
+        },
+    );
+    *counter += 2;
+    let mut moved = Value::Temporary(format_counter(*counter));
+    if wraps {
+        moved = wrap_address(moved, tape, counter, func);
+    }
+    func.add_instr(Instr::Store(
+        Type::Long,
+        Value::Temporary(format_counter(1)),
+        moved,
+    ));
+}
+
+/// Hand `address` over to `$bfc_tape_fix` if it isn't on the tape, going back to `retry` to work
+/// it out again if the tape policy could do something about it, or leaving `main` with an error
+/// pointing at `location` if it couldn't.
+///
+/// %.4 =l loadl $tape_start
+/// %.5 =l sub %.3, %.4
+/// %.6 =l loadl $tape_bytes
+/// %.7 =w csltl %.5, 0
+/// %.8 =w csgel %.5, %.6
+/// %.9 =w or %.7, %.8
+/// jnz %.9, @oob.9, @inbounds.9
+/// @oob.9
+///     %.10 =w call $bfc_tape_fix(l %.1, l %.5)
+///     jnz %.10, @retry, @fail.9
+/// @fail.9
+///     %.11 =l loadl $stderr
+///     call $fputs(l $bounds.line.column, l %.11)
+///     ret 1
+/// @inbounds.9
+fn check_tape(
+    address: &Value,
+    location: (u32, u32),
+    retry: String,
+    tape: &mut Tape,
+    counter: &mut i32,
+    func: &mut Function<'_>,
+) {
+    func.assign_instr(
+        Value::Temporary(format_counter(*counter + 1)),
+        Type::Long,
+        Instr::Load(Type::Long, Value::Global("tape_start".to_owned())),
+    );
+    let from_start = Value::Temporary(format_counter(*counter + 2));
+    func.assign_instr(
+        from_start.clone(),
+        Type::Long,
+        Instr::Sub(
+            address.clone(),
+            Value::Temporary(format_counter(*counter + 1)),
+        ),
+    );
+    func.assign_instr(
+        Value::Temporary(format_counter(*counter + 3)),
+        Type::Long,
+        Instr::Load(Type::Long, Value::Global("tape_bytes".to_owned())),
+    );
+    func.assign_instr(
+        Value::Temporary(format_counter(*counter + 4)),
+        Type::Word,
+        Instr::Cmp(Type::Long, Cmp::Slt, from_start.clone(), Value::Const(0)),
+    );
+    func.assign_instr(
+        Value::Temporary(format_counter(*counter + 5)),
+        Type::Word,
+        Instr::Cmp(
+            Type::Long,
+            Cmp::Sge,
+            from_start.clone(),
+            Value::Temporary(format_counter(*counter + 3)),
+        ),
+    );
+    func.assign_instr(
+        Value::Temporary(format_counter(*counter + 6)),
+        Type::Word,
+        Instr::Or(
+            Value::Temporary(format_counter(*counter + 4)),
+            Value::Temporary(format_counter(*counter + 5)),
+        ),
+    );
+    *counter += 6;
+    let outside = format!("oob.{counter}");
+    let inside = format!("inbounds.{counter}");
+    let fail = format!("fail.{counter}");
+    func.add_instr(Instr::Jnz(
+        Value::Temporary(format_counter(*counter)),
+        outside.clone(),
+        inside.clone(),
+    ));
+    func.add_block(outside);
+    func.assign_instr(
+        Value::Temporary(format_counter(*counter + 1)),
+        Type::Word,
+        Instr::Call(
+            "bfc_tape_fix".to_owned(),
+            vec![
+                (Type::Long, Value::Temporary(format_counter(1))),
+                (Type::Long, from_start),
+            ],
+        ),
+    );
+    func.add_instr(Instr::Jnz(
+        Value::Temporary(format_counter(*counter + 1)),
+        retry,
+        fail.clone(),
+    ));
+    func.add_block(fail);
+    func.assign_instr(
+        Value::Temporary(format_counter(*counter + 2)),
+        Type::Long,
+        Instr::Load(Type::Long, Value::Global("stderr".to_owned())),
+    );
+    func.add_instr(Instr::Call(
+        "fputs".to_owned(),
+        vec![
+            (
+                Type::Long,
+                Value::Global(format_bounds_message(location.0, location.1)),
+            ),
+            (Type::Long, Value::Temporary(format_counter(*counter + 2))),
+        ],
+    ));
+    func.add_instr(Instr::Ret(Some(Value::Const(1))));
+    func.add_block(inside);
+    *counter += 2;
+    tape.checks.insert(location);
+}
+
+/// The runtime half of the tape policy. `$bfc_tape_fix(l pointer, l distance)` gets where the
+/// pointer is kept and how many bytes from the start of the tape an access landed that missed it,
+/// and returns 1 once the access is worth trying again or 0 to give up.
+///
+/// Growing doubles the tape with `realloc` and moves the pointer along with it. Wrapping never
+/// needs this, `wrap_address` takes care of it right where the pointer moves.
+fn tape_fix(policy: TapePolicy) -> Function<'static> {
+    let mut func = Function::new(
+        Linkage::private(),
+        "bfc_tape_fix".to_owned(),
+        vec![
+            (Type::Long, Value::Temporary("pointer".to_owned())),
+            (Type::Long, Value::Temporary("distance".to_owned())),
+        ],
+        Some(Type::Word),
+    );
+    func.add_block("start".to_owned());
+    if policy == TapePolicy::Abort {
+        func.add_instr(Instr::Ret(Some(Value::Const(0))));
+        return func;
+    }
+    func.assign_instr(
+        Value::Temporary("below".to_owned()),
+        Type::Word,
+        Instr::Cmp(
+            Type::Long,
+            Cmp::Slt,
+            Value::Temporary("distance".to_owned()),
+            Value::Const(0),
+        ),
+    );
+    func.add_instr(Instr::Jnz(
+        Value::Temporary("below".to_owned()),
+        "left".to_owned(),
+        "right".to_owned(),
+    ));
+    match policy {
+        TapePolicy::GrowRight => {
+            func.add_block("left".to_owned());
+            func.add_instr(Instr::Ret(Some(Value::Const(0))));
+            grow_tape(&mut func, "right");
+        }
+        TapePolicy::GrowBoth => {
+            grow_tape(&mut func, "left");
+            grow_tape(&mut func, "right");
+        }
+        TapePolicy::Wrap | TapePolicy::Abort => unreachable!(),
+    }
+    func.add_block("fail".to_owned());
+    func.add_instr(Instr::Ret(Some(Value::Const(0))));
+    func
+}
+
+/// Double the tape, putting the new (zeroed) half on `side`:
+///
+/// @right
+///     %right.size =l loadl $tape_bytes
+///     %right.start =l loadl $tape_start
+///     %right.bytes =l mul %right.size, 2
+///     %right.tape =l call $realloc(l %right.start, l %right.bytes)
+///     %right.allocated =w cnel %right.tape, 0
+///     jnz %right.allocated, @right.moved, @fail
+/// @right.moved
+///     %right.half =l add %right.tape, %right.size
+///     call $memset(l %right.half, w 0, l %right.size)
+///     ...then move the pointer over to the new tape and store the new start and size
+///
+/// Growing on the left first moves the old cells up into the new half with `memmove`, and moves
+/// the pointer up with them.
+fn grow_tape(func: &mut Function<'_>, side: &str) {
+    let temporary = |name: &str| Value::Temporary(format!("{side}.{name}"));
+    func.add_block(side.to_owned());
+    func.assign_instr(
+        temporary("size"),
+        Type::Long,
+        Instr::Load(Type::Long, Value::Global("tape_bytes".to_owned())),
+    );
+    func.assign_instr(
+        temporary("start"),
+        Type::Long,
+        Instr::Load(Type::Long, Value::Global("tape_start".to_owned())),
+    );
+    func.assign_instr(
+        temporary("bytes"),
+        Type::Long,
+        Instr::Mul(temporary("size"), Value::Const(2)),
+    );
+    func.assign_instr(
+        temporary("tape"),
+        Type::Long,
+        Instr::Call(
+            "realloc".to_owned(),
+            vec![
+                (Type::Long, temporary("start")),
+                (Type::Long, temporary("bytes")),
+            ],
+        ),
+    );
+    // `jnz` only looks at a word, so compare the whole pointer instead
+    func.assign_instr(
+        temporary("allocated"),
+        Type::Word,
+        Instr::Cmp(Type::Long, Cmp::Ne, temporary("tape"), Value::Const(0)),
+    );
+    func.add_instr(Instr::Jnz(
+        temporary("allocated"),
+        format!("{side}.moved"),
+        "fail".to_owned(),
+    ));
+    func.add_block(format!("{side}.moved"));
+    func.assign_instr(
+        temporary("half"),
+        Type::Long,
+        Instr::Add(temporary("tape"), temporary("size")),
+    );
+    // Where the old cells and the pointer end up
+    let (cells, zeroed) = if side == "left" {
+        func.add_instr(Instr::Call(
+            "memmove".to_owned(),
+            vec![
+                (Type::Long, temporary("half")),
+                (Type::Long, temporary("tape")),
+                (Type::Long, temporary("size")),
+            ],
+        ));
+        (temporary("half"), temporary("tape"))
+    } else {
+        (temporary("tape"), temporary("half"))
+    };
+    func.add_instr(Instr::Call(
+        "memset".to_owned(),
+        vec![
+            (Type::Long, zeroed),
+            (Type::Word, Value::Const(0)),
+            (Type::Long, temporary("size")),
+        ],
+    ));
+    func.assign_instr(
+        temporary("old"),
+        Type::Long,
+        Instr::Load(Type::Long, Value::Temporary("pointer".to_owned())),
+    );
+    func.assign_instr(
+        temporary("offset"),
+        Type::Long,
+        Instr::Sub(temporary("old"), temporary("start")),
+    );
+    func.assign_instr(
+        temporary("new"),
+        Type::Long,
+        Instr::Add(cells, temporary("offset")),
+    );
+    func.add_instr(Instr::Store(
+        Type::Long,
+        Value::Temporary("pointer".to_owned()),
+        temporary("new"),
+    ));
+    func.add_instr(Instr::Store(
+        Type::Long,
+        Value::Global("tape_start".to_owned()),
+        temporary("tape"),
+    ));
+    func.add_instr(Instr::Store(
+        Type::Long,
+        Value::Global("tape_bytes".to_owned()),
+        temporary("bytes"),
+    ));
+    func.add_instr(Instr::Ret(Some(Value::Const(1))));
+}
+
+/// `$bfc_put_utf8(w value) -> w` writes one code point to stdout as UTF-8, or U+FFFD for anything
+/// that can't be one (surrogates and everything past U+10FFFF):
+///
+/// @start
+///     %code =w copy %value
+///     %bad.high =w cugtw %code, 1114111
+///     ...
+///     jnz %bad, @replace, @one
+/// @one
+///     %ascii =w cultw %code, 128
+///     jnz %ascii, @one.put, @two
+/// @one.put
+///     call $putchar(w %code)
+///     ret 0
+/// @two
+///     ...and so on up to four bytes
+fn put_utf8() -> Function<'static> {
+    let temporary = |name: &str| Value::Temporary(name.to_owned());
+    let mut func = Function::new(
+        Linkage::private(),
+        "bfc_put_utf8".to_owned(),
+        vec![(Type::Word, temporary("value"))],
+        Some(Type::Word),
+    );
+    func.add_block("start".to_owned());
+    func.assign_instr(
+        temporary("code"),
+        Type::Word,
+        Instr::Copy(temporary("value")),
+    );
+    func.assign_instr(
+        temporary("bad.high"),
+        Type::Word,
+        Instr::Cmp(
+            Type::Word,
+            Cmp::Ugt,
+            temporary("code"),
+            Value::Const(0x10ffff),
+        ),
+    );
+    func.assign_instr(
+        temporary("bad.above"),
+        Type::Word,
+        Instr::Cmp(
+            Type::Word,
+            Cmp::Uge,
+            temporary("code"),
+            Value::Const(0xd800),
+        ),
+    );
+    func.assign_instr(
+        temporary("bad.below"),
+        Type::Word,
+        Instr::Cmp(
+            Type::Word,
+            Cmp::Ult,
+            temporary("code"),
+            Value::Const(0xe000),
+        ),
+    );
+    func.assign_instr(
+        temporary("bad.surrogate"),
+        Type::Word,
+        Instr::And(temporary("bad.above"), temporary("bad.below")),
+    );
+    func.assign_instr(
+        temporary("bad"),
+        Type::Word,
+        Instr::Or(temporary("bad.high"), temporary("bad.surrogate")),
+    );
+    func.add_instr(Instr::Jnz(
+        temporary("bad"),
+        "replace".to_owned(),
+        "one".to_owned(),
+    ));
+    func.add_block("replace".to_owned());
+    func.assign_instr(
+        temporary("code"),
+        Type::Word,
+        Instr::Copy(Value::Const(u64::from(char::REPLACEMENT_CHARACTER))),
+    );
+    func.add_instr(Instr::Jmp("three".to_owned()));
+    // One block per length, falling through to the next one when the code point doesn't fit:
+    // (block, code points it fits, what marks the lead byte)
+    let lengths = [
+        ("one", 0x80, 0x00),
+        ("two", 0x800, 0xc0),
+        ("three", 0x10000, 0xe0),
+        ("four", 0x110000, 0xf0),
+    ];
+    for (block, (name, limit, lead)) in lengths.into_iter().enumerate() {
+        func.add_block(name.to_owned());
+        if let Some((next, ..)) = lengths.get(block + 1) {
+            func.assign_instr(
+                temporary(&format!("{name}.fits")),
+                Type::Word,
+                Instr::Cmp(Type::Word, Cmp::Ult, temporary("code"), Value::Const(limit)),
+            );
+            func.add_instr(Instr::Jnz(
+                temporary(&format!("{name}.fits")),
+                format!("{name}.put"),
+                (*next).to_owned(),
+            ));
+            func.add_block(format!("{name}.put"));
+        }
+        // The lead byte gets whatever's left above the 6 bit continuation bytes
+        for byte in (0..=block).rev() {
+            let shifted = temporary(&format!("{name}.shifted.{byte}"));
+            func.assign_instr(
+                shifted.clone(),
+                Type::Word,
+                Instr::Div(temporary("code"), Value::Const(1 << (6 * byte))),
+            );
+            let (bits, mark) = if byte == block {
+                (shifted, lead)
+            } else {
+                let bits = temporary(&format!("{name}.bits.{byte}"));
+                func.assign_instr(
+                    bits.clone(),
+                    Type::Word,
+                    Instr::And(shifted, Value::Const(0x3f)),
+                );
+                (bits, 0x80)
+            };
+            let out = temporary(&format!("{name}.byte.{byte}"));
+            func.assign_instr(out.clone(), Type::Word, Instr::Or(bits, Value::Const(mark)));
+            func.add_instr(Instr::Call("putchar".to_owned(), vec![(Type::Word, out)]));
+        }
+        func.add_instr(Instr::Ret(Some(Value::Const(0))));
+    }
+    func
+}
+
+/// `$bfc_get_utf8() -> w` reads one UTF-8 encoded code point from stdin, just like
+/// `Machine::read_code_point`: -1 once stdin runs out, and U+FFFD for a bad first byte or a
+/// sequence that's cut short.
+///
+/// @start
+///     %byte =w call $getchar()
+///     %ascii =w cultw %byte, 128
+///     jnz %ascii, @done.byte, @two
+/// @two
+///     %two.lead =w and %byte, 224
+///     %two.match =w ceqw %two.lead, 192
+///     jnz %two.match, @two.start, @three
+/// @two.start
+///     %code =w and %byte, 31
+///     %left =w copy 1
+///     jmp @more
+///     ...and the same for three and four bytes, then for each byte left:
+/// @more
+///     %next =w call $getchar()
+///     ...
+///     %code =w or %code.shifted, %next.bits
+///     %left =w sub %left, 1
+///     jnz %left, @more, @done
+fn get_utf8() -> Function<'static> {
+    let temporary = |name: &str| Value::Temporary(name.to_owned());
+    let mut func = Function::new(
+        Linkage::private(),
+        "bfc_get_utf8".to_owned(),
+        Vec::new(),
+        Some(Type::Word),
+    );
+    func.add_block("start".to_owned());
+    func.assign_instr(
+        temporary("byte"),
+        Type::Word,
+        Instr::Call("getchar".to_owned(), vec![]),
+    );
+    // getchar's -1 is the biggest there is unsigned, so this only lets ASCII through
+    func.assign_instr(
+        temporary("ascii"),
+        Type::Word,
+        Instr::Cmp(Type::Word, Cmp::Ult, temporary("byte"), Value::Const(0x80)),
+    );
+    func.assign_instr(
+        temporary("eof"),
+        Type::Word,
+        Instr::Cmp(Type::Word, Cmp::Slt, temporary("byte"), Value::Const(0)),
+    );
+    func.assign_instr(
+        temporary("single"),
+        Type::Word,
+        Instr::Or(temporary("ascii"), temporary("eof")),
+    );
+    func.add_instr(Instr::Jnz(
+        temporary("single"),
+        "done.byte".to_owned(),
+        "two".to_owned(),
+    ));
+    func.add_block("done.byte".to_owned());
+    func.add_instr(Instr::Ret(Some(temporary("byte"))));
+    // (mask to match the lead byte, what it has to be, mask for its bits of the code point)
+    let leads = [
+        ("two", 0xe0, 0xc0, 0x1f),
+        ("three", 0xf0, 0xe0, 0x0f),
+        ("four", 0xf8, 0xf0, 0x07),
+    ];
+    for (more, (name, mask, lead, bits)) in leads.into_iter().enumerate() {
+        func.add_block(name.to_owned());
+        func.assign_instr(
+            temporary(&format!("{name}.lead")),
+            Type::Word,
+            Instr::And(temporary("byte"), Value::Const(mask)),
+        );
+        func.assign_instr(
+            temporary(&format!("{name}.match")),
+            Type::Word,
+            Instr::Cmp(
+                Type::Word,
+                Cmp::Eq,
+                temporary(&format!("{name}.lead")),
+                Value::Const(lead),
+            ),
+        );
+        func.add_instr(Instr::Jnz(
+            temporary(&format!("{name}.match")),
+            format!("{name}.start"),
+            leads
+                .get(more + 1)
+                .map_or("bad".to_owned(), |(next, ..)| (*next).to_owned()),
+        ));
+        func.add_block(format!("{name}.start"));
+        func.assign_instr(
+            temporary("code"),
+            Type::Word,
+            Instr::And(temporary("byte"), Value::Const(bits)),
+        );
+        func.assign_instr(
+            temporary("left"),
+            Type::Word,
+            Instr::Copy(Value::Const(more as u64 + 1)),
+        );
+        func.add_instr(Instr::Jmp("more".to_owned()));
+    }
+    func.add_block("more".to_owned());
+    func.assign_instr(
+        temporary("next"),
+        Type::Word,
+        Instr::Call("getchar".to_owned(), vec![]),
+    );
+    // -1 doesn't look like a continuation byte either
+    func.assign_instr(
+        temporary("next.top"),
+        Type::Word,
+        Instr::And(temporary("next"), Value::Const(0xc0)),
+    );
+    func.assign_instr(
+        temporary("next.continues"),
+        Type::Word,
+        Instr::Cmp(
+            Type::Word,
+            Cmp::Eq,
+            temporary("next.top"),
+            Value::Const(0x80),
+        ),
+    );
+    func.add_instr(Instr::Jnz(
+        temporary("next.continues"),
+        "next".to_owned(),
+        "bad".to_owned(),
+    ));
+    func.add_block("next".to_owned());
+    func.assign_instr(
+        temporary("code.shifted"),
+        Type::Word,
+        Instr::Mul(temporary("code"), Value::Const(64)),
+    );
+    func.assign_instr(
+        temporary("next.bits"),
+        Type::Word,
+        Instr::And(temporary("next"), Value::Const(0x3f)),
+    );
+    func.assign_instr(
+        temporary("code"),
+        Type::Word,
+        Instr::Or(temporary("code.shifted"), temporary("next.bits")),
+    );
+    func.assign_instr(
+        temporary("left"),
+        Type::Word,
+        Instr::Sub(temporary("left"), Value::Const(1)),
+    );
+    func.add_instr(Instr::Jnz(
+        temporary("left"),
+        "more".to_owned(),
+        "done".to_owned(),
+    ));
+    func.add_block("done".to_owned());
+    func.add_instr(Instr::Ret(Some(temporary("code"))));
+    func.add_block("bad".to_owned());
+    func.add_instr(Instr::Ret(Some(Value::Const(u64::from(
+        char::REPLACEMENT_CHARACTER,
+    )))));
+    func
+}
+
 fn generate_qbe(
     ast: &Vec<Tokens>,
     counter: &mut i32,
     while_counter: &mut i32,
+    tape: &mut Tape,
     debug: bool,
     func: &mut Function<'_>,
 ) {
@@ -132,7 +1025,7 @@ fn generate_qbe(
                 // pointer in the compiled program. So what do we do?
                 //
                 // %.2 =l loadl %.1
-                // %.3 =l add %.2, x*4
+                // %.3 =l add %.2, x*cell size
                 // storel %.3, %.1
                 //
                 // Recall that %.1 is assigned to an alloc8 8: this is just our pointer of sorts.
@@ -143,25 +1036,7 @@ fn generate_qbe(
                         Some(part.get_location().1 as u64),
                     ));
                 }
-                func.assign_instr(
-                    Value::Temporary(format_counter(*counter + 1)),
-                    Type::Long,
-                    Instr::Load(Type::Long, Value::Temporary(format_counter(1))),
-                );
-                func.assign_instr(
-                    Value::Temporary(format_counter(*counter + 2)),
-                    Type::Long,
-                    Instr::Add(
-                        Value::Temporary(format_counter(*counter + 1)),
-                        Value::Const(u64::from(*x) * 4),
-                    ),
-                );
-                func.add_instr(Instr::Store(
-                    Type::Long,
-                    Value::Temporary(format_counter(1)),
-                    Value::Temporary(format_counter(*counter + 2)),
-                ));
-                *counter += 2;
+                move_pointer(i64::from(*x), tape, counter, func);
             }
             OpCodes::Dec(x) => {
                 // Same for this one, but use Sub instead of Add
@@ -171,35 +1046,19 @@ fn generate_qbe(
                         Some(part.get_location().1 as u64),
                     ));
                 }
-                func.assign_instr(
-                    Value::Temporary(format_counter(*counter + 1)),
-                    Type::Long,
-                    Instr::Load(Type::Long, Value::Temporary(format_counter(1))),
-                );
-                func.assign_instr(
-                    Value::Temporary(format_counter(*counter + 2)),
-                    Type::Long,
-                    Instr::Sub(
-                        Value::Temporary(format_counter(*counter + 1)),
-                        Value::Const(u64::from(*x) * 4),
-                    ),
-                );
-                func.add_instr(Instr::Store(
-                    Type::Long,
-                    Value::Temporary(format_counter(1)),
-                    Value::Temporary(format_counter(*counter + 2)),
-                ));
-                *counter += 2;
+                move_pointer(-i64::from(*x), tape, counter, func);
             }
-            OpCodes::Add(x) => {
+            OpCodes::Add(x, offset) => {
                 // %.2 =l loadl %.1
-                // %.3 =w loadw %.2
-                // %.4 =w add %.3, x
-                // storew %.4, %.2
+                // %.3 =l add %.2, offset*4
+                // %.4 =w loadw %.3
+                // %.5 =w add %.4, x
+                // storew %.5, %.3
                 //
-                // So what this does is load our pointer into a register (%.2), then does a
-                // conversion to make it a word in %.3, then we add our x into what we got from
-                // %.3, then we store that computation into our original %.2 which loaded the
+                // So what this does is load our pointer into a register (%.2), moves it over to
+                // the cell we actually want (%.3, skipped when the offset is 0), then does a
+                // conversion to make it a word in %.4, then we add our x into what we got from
+                // %.4, then we store that computation into our original %.3 which loaded the
                 // pointer.
                 if debug {
                     func.add_instr(Instr::DbgLoc(
@@ -207,64 +1066,56 @@ fn generate_qbe(
                         Some(part.get_location().1 as u64),
                     ));
                 }
+                let address = cell_address(*offset, part.get_location(), tape, counter, func);
                 func.assign_instr(
                     Value::Temporary(format_counter(*counter + 1)),
-                    Type::Long,
-                    Instr::Load(Type::Long, Value::Temporary(format_counter(1))),
-                );
-                func.assign_instr(
-                    Value::Temporary(format_counter(*counter + 2)),
                     Type::Word,
-                    Instr::Load(Type::Word, Value::Temporary(format_counter(*counter + 1))),
+                    Instr::Load(load_type(tape.width), address.clone()),
                 );
                 func.assign_instr(
-                    Value::Temporary(format_counter(*counter + 3)),
+                    Value::Temporary(format_counter(*counter + 2)),
                     Type::Word,
                     Instr::Add(
-                        Value::Temporary(format_counter(*counter + 2)),
+                        Value::Temporary(format_counter(*counter + 1)),
                         Value::Const(*x as u64),
                     ),
                 );
                 func.add_instr(Instr::Store(
-                    Type::Word,
-                    Value::Temporary(format_counter(*counter + 1)),
-                    Value::Temporary(format_counter(*counter + 3)),
+                    store_type(tape.width),
+                    address,
+                    Value::Temporary(format_counter(*counter + 2)),
                 ));
-                *counter += 3;
+                *counter += 2;
             }
-            OpCodes::Sub(x) => {
+            OpCodes::Sub(x, offset) => {
                 if debug {
                     func.add_instr(Instr::DbgLoc(
                         part.get_location().0 as u64,
                         Some(part.get_location().1 as u64),
                     ));
                 }
+                let address = cell_address(*offset, part.get_location(), tape, counter, func);
                 func.assign_instr(
                     Value::Temporary(format_counter(*counter + 1)),
-                    Type::Long,
-                    Instr::Load(Type::Long, Value::Temporary(format_counter(1))),
-                );
-                func.assign_instr(
-                    Value::Temporary(format_counter(*counter + 2)),
                     Type::Word,
-                    Instr::Load(Type::Word, Value::Temporary(format_counter(*counter + 1))),
+                    Instr::Load(load_type(tape.width), address.clone()),
                 );
                 func.assign_instr(
-                    Value::Temporary(format_counter(*counter + 3)),
+                    Value::Temporary(format_counter(*counter + 2)),
                     Type::Word,
                     Instr::Sub(
-                        Value::Temporary(format_counter(*counter + 2)),
+                        Value::Temporary(format_counter(*counter + 1)),
                         Value::Const(*x as u64),
                     ),
                 );
                 func.add_instr(Instr::Store(
-                    Type::Word,
-                    Value::Temporary(format_counter(*counter + 1)),
-                    Value::Temporary(format_counter(*counter + 3)),
+                    store_type(tape.width),
+                    address,
+                    Value::Temporary(format_counter(*counter + 2)),
                 ));
-                *counter += 3;
+                *counter += 2;
             }
-            OpCodes::Output => {
+            OpCodes::Output(offset) => {
                 // Same drill, we load our pointer, then we run the C putchar function on our
                 // loaded pointer
                 if debug {
@@ -273,49 +1124,94 @@ fn generate_qbe(
                         Some(part.get_location().1 as u64),
                     ));
                 }
+                let address = cell_address(*offset, part.get_location(), tape, counter, func);
                 func.assign_instr(
                     Value::Temporary(format_counter(*counter + 1)),
-                    Type::Long,
-                    Instr::Load(Type::Long, Value::Temporary(format_counter(1))),
-                );
-                func.assign_instr(
-                    Value::Temporary(format_counter(*counter + 2)),
                     Type::Word,
-                    Instr::Load(Type::Word, Value::Temporary(format_counter(*counter + 1))),
+                    Instr::Load(load_type(tape.width), address),
                 );
                 func.assign_instr(
-                    Value::Temporary(format_counter(*counter + 3)),
+                    Value::Temporary(format_counter(*counter + 2)),
                     Type::Word,
                     Instr::Call(
-                        "putchar".to_owned(),
-                        vec![(Type::Word, Value::Temporary(format_counter(*counter + 2)))],
+                        match tape.io {
+                            IoMode::Bytes => "putchar".to_owned(),
+                            IoMode::Utf8 => "bfc_put_utf8".to_owned(),
+                        },
+                        vec![(Type::Word, Value::Temporary(format_counter(*counter + 1)))],
                     ),
                 );
-                *counter += 3;
+                *counter += 2;
             }
-            OpCodes::Input => {
+            OpCodes::Input(offset) => {
                 if debug {
                     func.add_instr(Instr::DbgLoc(
                         part.get_location().0 as u64,
                         Some(part.get_location().1 as u64),
                     ));
                 }
+                // %.2 =w call $getchar()
+                //
+                // getchar (and `$bfc_get_utf8`) hands back -1 once it runs out, which is already what `--eof -1` wants
+                // stored. For `--eof 0` we add 1 to exactly that -1:
+                //
+                // %.3 =w csltw %.2, 0
+                // %.4 =w add %.2, %.3
+                //
+                // and `--eof unchanged` jumps over the store altogether, so it never even looks
+                // at the cell:
+                //
+                // jnz %.3, @loop.N+1, @loop.N
+                // @loop.N
+                //     <store %.2>
+                // @loop.N+1
+                let character = Value::Temporary(format_counter(*counter + 1));
                 func.assign_instr(
-                    Value::Temporary(format_counter(*counter + 1)),
+                    character.clone(),
                     Type::Word,
-                    Instr::Call("getchar".to_owned(), vec![]),
-                );
-                func.assign_instr(
-                    Value::Temporary(format_counter(*counter + 2)),
-                    Type::Long,
-                    Instr::Load(Type::Long, Value::Temporary(format_counter(1))),
+                    Instr::Call(
+                        match tape.io {
+                            IoMode::Bytes => "getchar".to_owned(),
+                            IoMode::Utf8 => "bfc_get_utf8".to_owned(),
+                        },
+                        vec![],
+                    ),
                 );
-                func.add_instr(Instr::Store(
-                    Type::Word,
-                    Value::Temporary(format_counter(*counter + 2)),
-                    Value::Temporary(format_counter(*counter + 1)),
-                ));
-                *counter += 2;
+                *counter += 1;
+                let mut skip = None;
+                let character = if tape.eof == EofPolicy::MinusOne {
+                    character
+                } else {
+                    let eof = Value::Temporary(format_counter(*counter + 1));
+                    func.assign_instr(
+                        eof.clone(),
+                        Type::Word,
+                        Instr::Cmp(Type::Word, Cmp::Slt, character.clone(), Value::Const(0)),
+                    );
+                    *counter += 1;
+                    if tape.eof == EofPolicy::Zero {
+                        func.assign_instr(
+                            Value::Temporary(format_counter(*counter + 1)),
+                            Type::Word,
+                            Instr::Add(character, eof),
+                        );
+                        *counter += 1;
+                        Value::Temporary(format_counter(*counter))
+                    } else {
+                        let store = format_label(*while_counter);
+                        let after = format_label(*while_counter + 1);
+                        *while_counter += 2;
+                        func.add_instr(Instr::Jnz(eof, after.clone(), store.clone()));
+                        func.add_block(store);
+                        skip = Some(after);
+                        character
+                    }
+                };
+                let address = cell_address(*offset, part.get_location(), tape, counter, func);
+                func.add_instr(Instr::Store(store_type(tape.width), address, character));
+                if let Some(skip) = skip {
+                    func.add_block(skip);
+                }
             }
             OpCodes::Loop(ast) => {
                 if debug {
@@ -325,31 +1221,27 @@ fn generate_qbe(
                     ));
                 }
                 func.add_block(format_label(*while_counter));
+                let pointer = cell_address(0, part.get_location(), tape, counter, func);
                 func.assign_instr(
                     Value::Temporary(format_counter(*counter + 1)),
-                    Type::Long,
-                    Instr::Load(Type::Long, Value::Temporary(format_counter(1))),
-                );
-                func.assign_instr(
-                    Value::Temporary(format_counter(*counter + 2)),
                     Type::Word,
-                    Instr::Load(Type::Word, Value::Temporary(format_counter(*counter + 1))),
+                    Instr::Load(load_type(tape.width), pointer),
                 );
                 func.add_instr(Instr::Jnz(
-                    Value::Temporary(format_counter(*counter + 2)),
+                    Value::Temporary(format_counter(*counter + 1)),
                     format_label(*while_counter + 1),
                     format_label(*while_counter + 2),
                 ));
                 func.add_block(format_label(*while_counter + 1));
-                *counter += 3;
+                *counter += 1;
                 let returned_while = *while_counter;
                 *while_counter += 3;
-                generate_qbe(ast, counter, while_counter, debug, func);
+                generate_qbe(ast, counter, while_counter, tape, debug, func);
                 func.add_instr(Instr::Jmp(format_label(returned_while)));
                 func.add_block(format_label(returned_while + 2));
             }
-            OpCodes::Clear => {
-                // Functions basically the same as `OpCodes::Inc/Dec` but instead of running add or
+            OpCodes::Clear(offset) => {
+                // Functions basically the same as `OpCodes::Add/Sub` but instead of running add or
                 // sub on it, we just copy the value 0.
                 if debug {
                     func.add_instr(Instr::DbgLoc(
@@ -357,28 +1249,237 @@ fn generate_qbe(
                         Some(part.get_location().1 as u64),
                     ));
                 }
+                let address = cell_address(*offset, part.get_location(), tape, counter, func);
                 func.assign_instr(
                     Value::Temporary(format_counter(*counter + 1)),
-                    Type::Long,
-                    Instr::Load(Type::Long, Value::Temporary(format_counter(1))),
+                    Type::Word,
+                    Instr::Copy(Value::Const(0)),
                 );
+                func.add_instr(Instr::Store(
+                    store_type(tape.width),
+                    address,
+                    Value::Temporary(format_counter(*counter + 1)),
+                ));
+                *counter += 1;
+            }
+            OpCodes::Set(x, offset) => {
+                // Same as `OpCodes::Clear`, just copying x instead of 0
+                if debug {
+                    func.add_instr(Instr::DbgLoc(
+                        part.get_location().0 as u64,
+                        Some(part.get_location().1 as u64),
+                    ));
+                }
+                let address = cell_address(*offset, part.get_location(), tape, counter, func);
                 func.assign_instr(
-                    Value::Temporary(format_counter(*counter + 2)),
+                    Value::Temporary(format_counter(*counter + 1)),
                     Type::Word,
-                    Instr::Load(Type::Word, Value::Temporary(format_counter(*counter + 1))),
+                    Instr::Copy(Value::Const(u64::from(*x))),
                 );
+                func.add_instr(Instr::Store(
+                    store_type(tape.width),
+                    address,
+                    Value::Temporary(format_counter(*counter + 1)),
+                ));
+                *counter += 1;
+            }
+            OpCodes::MulAdd(targets) => {
+                // %.2 =l loadl %.1
+                // %.3 =w loadw %.2
+                //
+                // Then for every (offset, factor), with %.5 worked out like `OpCodes::Add` does:
+                //
+                // %.6 =w loadw %.5
+                // %.7 =w mul %.3, factor
+                // %.8 =w add %.6, %.7
+                // storew %.8, %.5
+                //
+                // And finally `storew 0, %.2`. All of that sits behind a `jnz %.3`, because the
+                // loop this came from never touches the other cells when the current one is 0,
+                // and they might not even be on the tape.
+                if debug {
+                    func.add_instr(Instr::DbgLoc(
+                        part.get_location().0 as u64,
+                        Some(part.get_location().1 as u64),
+                    ));
+                }
+                let pointer = cell_address(0, part.get_location(), tape, counter, func);
+                let value = Value::Temporary(format_counter(*counter + 1));
                 func.assign_instr(
-                    Value::Temporary(format_counter(*counter + 3)),
+                    value.clone(),
                     Type::Word,
-                    Instr::Copy(Value::Const(0)),
+                    Instr::Load(load_type(tape.width), pointer),
                 );
+                *counter += 1;
+                func.add_instr(Instr::Jnz(
+                    value.clone(),
+                    format_label(*while_counter),
+                    format_label(*while_counter + 1),
+                ));
+                func.add_block(format_label(*while_counter));
+                let skip = format_label(*while_counter + 1);
+                *while_counter += 2;
+                for (offset, factor) in targets {
+                    let address = cell_address(*offset, part.get_location(), tape, counter, func);
+                    func.assign_instr(
+                        Value::Temporary(format_counter(*counter + 1)),
+                        Type::Word,
+                        Instr::Load(load_type(tape.width), address.clone()),
+                    );
+                    func.assign_instr(
+                        Value::Temporary(format_counter(*counter + 2)),
+                        Type::Word,
+                        Instr::Mul(value.clone(), Value::Const(u64::from(*factor as u32))),
+                    );
+                    func.assign_instr(
+                        Value::Temporary(format_counter(*counter + 3)),
+                        Type::Word,
+                        Instr::Add(
+                            Value::Temporary(format_counter(*counter + 1)),
+                            Value::Temporary(format_counter(*counter + 2)),
+                        ),
+                    );
+                    func.add_instr(Instr::Store(
+                        store_type(tape.width),
+                        address,
+                        Value::Temporary(format_counter(*counter + 3)),
+                    ));
+                    *counter += 3;
+                }
+                // Growing the tape moves it somewhere else, so the current cell has to be looked up
+                // again
+                let pointer = cell_address(0, part.get_location(), tape, counter, func);
                 func.add_instr(Instr::Store(
+                    store_type(tape.width),
+                    pointer,
+                    Value::Const(0),
+                ));
+                func.add_block(skip);
+            }
+            OpCodes::Scan(stride) => {
+                // This is a loop like `OpCodes::Loop` but without a body to generate, so the whole
+                // thing stays a tight little loop around the pointer:
+                //
+                // @loop.1
+                //     %.2 =l loadl %.1
+                //     %.3 =w loadw %.2
+                //     jnz %.3, @loop.2, @loop.3
+                // @loop.2
+                //     %.4 =l add %.2, stride*cell size
+                //     storel %.4, %.1
+                //     jmp @loop.1
+                // @loop.3
+                if debug {
+                    func.add_instr(Instr::DbgLoc(
+                        part.get_location().0 as u64,
+                        Some(part.get_location().1 as u64),
+                    ));
+                }
+                func.add_block(format_label(*while_counter));
+                let pointer = cell_address(0, part.get_location(), tape, counter, func);
+                func.assign_instr(
+                    Value::Temporary(format_counter(*counter + 1)),
                     Type::Word,
+                    Instr::Load(load_type(tape.width), pointer),
+                );
+                func.add_instr(Instr::Jnz(
                     Value::Temporary(format_counter(*counter + 1)),
-                    Value::Temporary(format_counter(*counter + 3)),
+                    format_label(*while_counter + 1),
+                    format_label(*while_counter + 2),
+                ));
+                *counter += 1;
+                func.add_block(format_label(*while_counter + 1));
+                let moved = cell_address(*stride, part.get_location(), tape, counter, func);
+                func.add_instr(Instr::Store(
+                    Type::Long,
+                    Value::Temporary(format_counter(1)),
+                    moved,
                 ));
-                *counter += 3;
+                func.add_instr(Instr::Jmp(format_label(*while_counter)));
+                func.add_block(format_label(*while_counter + 2));
+                *while_counter += 3;
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::ast::generate_ast;
+
+    fn ir(program: &str, cell_width: CellWidth, tape: TapePolicy) -> String {
+        let ast = generate_ast(&mut program.chars()).unwrap();
+        let options = Options {
+            cell_width,
+            tape,
+            ..Options::default()
+        };
+        compile(&ast, &options, false, "test.b").0
+    }
+
+    #[test]
+    fn tape_allocation() {
+        assert!(ir("+", CellWidth::Eight, TapePolicy::Wrap).contains("call $calloc(l 30000, l 1)"));
+        assert!(
+            ir("+", CellWidth::Sixteen, TapePolicy::Wrap).contains("call $calloc(l 30000, l 2)")
+        );
+        let wide = ir("+", CellWidth::ThirtyTwo, TapePolicy::Wrap);
+        assert!(wide.contains("call $calloc(l 30000, l 4)"));
+        // Kept in bytes, not cells
+        assert!(wide
+            .lines()
+            .any(|line| line.starts_with("data $tape_bytes") && line.contains("l 120000 ")));
+    }
+
+    #[test]
+    fn bounds_messages() {
+        let program = "+>.\n<<,>";
+        let abort = ir(program, CellWidth::Eight, TapePolicy::Abort);
+        // `+`, `.` and `,` touch a cell, and only they can be off the tape
+        for (line, column) in [(1, 1), (1, 3), (2, 3)] {
+            assert!(abort.contains(&format!("call $fputs(l $bounds.{line}.{column}, ")));
+            let message = format!("\"Pointer left the tape at line {line}, column {column}\"");
+            assert_eq!(abort.matches(&message).count(), 1);
+        }
+        assert_eq!(abort.matches("Pointer left the tape").count(), 3);
+        assert!(!abort.contains("$realloc"));
+        assert!(!ir(program, CellWidth::Eight, TapePolicy::Wrap).contains("$bounds."));
+    }
+
+    #[test]
+    fn growing() {
+        let right = ir("<+", CellWidth::Sixteen, TapePolicy::GrowRight);
+        assert_eq!(right.matches("call $realloc(").count(), 1);
+        assert!(!right.contains("$memmove"));
+        let both = ir("<+", CellWidth::Sixteen, TapePolicy::GrowBoth);
+        assert_eq!(both.matches("call $realloc(").count(), 2);
+        assert!(both.contains("call $memmove("));
+        // Every access that misses the tape gets it fixed up, then works the address out again
+        assert!(both.contains("call $bfc_tape_fix(l %.1, "));
+        assert!(!ir("<+", CellWidth::Sixteen, TapePolicy::Wrap).contains("$bfc_tape_fix"));
+    }
+
+    #[test]
+    fn cell_widths() {
+        let accesses = |ir: &str| {
+            ["loadub", "loaduh", "loadw", "storeb", "storeh", "storew"]
+                .into_iter()
+                .filter(|access| ir.split_whitespace().any(|word| word == *access))
+                .collect::<Vec<_>>()
+        };
+        // Every cell gets read and written at its own width, and nothing else
+        assert_eq!(
+            accesses(&ir("+,.", CellWidth::Eight, TapePolicy::Wrap)),
+            ["loadub", "storeb"]
+        );
+        assert_eq!(
+            accesses(&ir("+,.", CellWidth::Sixteen, TapePolicy::Wrap)),
+            ["loaduh", "storeh"]
+        );
+        assert_eq!(
+            accesses(&ir("+,.", CellWidth::ThirtyTwo, TapePolicy::Wrap)),
+            ["loadw", "storew"]
+        );
+    }
+}
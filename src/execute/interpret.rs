@@ -1,22 +1,422 @@
-use crate::execute::machine::Machine;
-use crate::parse::opcodes::OpCodes;
+use crate::error::BfcError;
+use crate::execute::bytecode::{Bytecode, Instruction};
+use crate::execute::machine::{Cell, Limits, Machine, Trap};
 use crate::parse::opcodes::Tokens;
+use std::fmt;
+use std::io::{Read, Write};
 
-pub fn interpret(ast: &Vec<Tokens>, machine: &mut Machine) {
-    for op in ast {
-        match op.get_type() {
-            OpCodes::Inc(x) => machine.increment(*x as usize),
-            OpCodes::Dec(x) => machine.decrement(*x as usize),
-            OpCodes::Clear => machine.set_byte(0),
-            OpCodes::Add(x) => machine.add(*x),
-            OpCodes::Sub(x) => machine.sub(*x),
-            OpCodes::Input => machine.input(),
-            OpCodes::Output => machine.output(),
-            OpCodes::Loop(x) => {
-                while machine.get_byte() != 0 {
-                    interpret(x, machine);
-                }
+/// How a run ended, short of an error. Anything else than `Finished` means the program got
+/// stopped at `location` (line, column), by one of the machine's `Limits` or for never being
+/// able to finish.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExecOutcome {
+    Finished,
+    /// `steps` instructions ran and there's more to go.
+    FuelExhausted {
+        steps: u64,
+        location: (u32, u32),
+    },
+    /// The tape would have had to grow past `cells`.
+    TapeExhausted {
+        cells: usize,
+        location: (u32, u32),
+    },
+    /// The output would have gone past `bytes`.
+    OutputExhausted {
+        bytes: u64,
+        location: (u32, u32),
+    },
+    /// A scan on a wrapping tape with no zero cell for it to find, which would go around
+    /// forever. With a limit on steps that's `FuelExhausted` instead.
+    Endless {
+        location: (u32, u32),
+    },
+}
+
+impl fmt::Display for ExecOutcome {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExecOutcome::Finished => write!(fmt, "Finished"),
+            ExecOutcome::FuelExhausted {
+                steps,
+                location: (line, column),
+            } => write!(
+                fmt,
+                "Stopped after {steps} steps at line {line}, column {column}"
+            ),
+            ExecOutcome::TapeExhausted {
+                cells,
+                location: (line, column),
+            } => write!(
+                fmt,
+                "Tape would grow past {cells} cells at line {line}, column {column}"
+            ),
+            ExecOutcome::OutputExhausted {
+                bytes,
+                location: (line, column),
+            } => write!(
+                fmt,
+                "Output would go past {bytes} bytes at line {line}, column {column}"
+            ),
+            ExecOutcome::Endless {
+                location: (line, column),
+            } => write!(
+                fmt,
+                "Would loop forever at line {line}, column {column}, there's no zero cell to find"
+            ),
+        }
+    }
+}
+
+pub fn interpret<C: Cell, R: Read, W: Write>(
+    ast: &[Tokens],
+    machine: &mut Machine<C, R, W>,
+) -> Result<ExecOutcome, BfcError> {
+    run(&Bytecode::lower(ast), machine)
+}
+
+/// Run already lowered `bytecode` from the start until it falls off the end, or runs out of
+/// steps.
+pub fn run<C: Cell, R: Read, W: Write>(
+    bytecode: &Bytecode,
+    machine: &mut Machine<C, R, W>,
+) -> Result<ExecOutcome, BfcError> {
+    // Counting steps costs, so only do it when there's a limit on them
+    match machine.get_limits().steps {
+        Some(steps) => run_counting::<true, C, R, W>(bytecode, machine, steps),
+        None => run_counting::<false, C, R, W>(bytecode, machine, 0),
+    }
+}
+
+fn run_counting<const COUNTING: bool, C: Cell, R: Read, W: Write>(
+    bytecode: &Bytecode,
+    machine: &mut Machine<C, R, W>,
+    steps: u64,
+) -> Result<ExecOutcome, BfcError> {
+    let code = bytecode.get_code();
+    // Scans still take from it when not counting, it just never runs out
+    let mut fuel = if COUNTING { steps } else { u64::MAX };
+    let mut pc = 0;
+    while let Some(&instruction) = code.get(pc) {
+        if COUNTING {
+            if fuel == 0 {
+                return Ok(ExecOutcome::FuelExhausted {
+                    steps,
+                    location: bytecode.get_location(pc),
+                });
             }
+            fuel -= 1;
         }
+        match step(bytecode, instruction, pc, machine, &mut fuel) {
+            Ok(next) => pc = next,
+            Err(trap) => return stopped(trap, bytecode.get_location(pc), &machine.get_limits()),
+        }
+    }
+    Ok(ExecOutcome::Finished)
+}
+
+/// Carry out the instruction at `pc`, giving back where to go next. Scans can take more of
+/// `fuel` than the step the instruction itself cost.
+#[inline(always)]
+pub(crate) fn step<C: Cell, R: Read, W: Write>(
+    bytecode: &Bytecode,
+    instruction: Instruction,
+    pc: usize,
+    machine: &mut Machine<C, R, W>,
+    fuel: &mut u64,
+) -> Result<usize, Trap> {
+    match instruction {
+        Instruction::Inc(x) => machine.increment(x as usize),
+        Instruction::Dec(x) => machine.decrement(x as usize),
+        Instruction::Clear(offset) => machine.set_byte(offset, 0)?,
+        Instruction::Set(x, offset) => machine.set_byte(offset, x)?,
+        Instruction::Add(x, offset) => machine.add(offset, x)?,
+        Instruction::Sub(x, offset) => machine.sub(offset, x)?,
+        Instruction::Input(offset) => machine.input(offset)?,
+        Instruction::Output(offset) => machine.output(offset)?,
+        Instruction::MulAdd(index) => machine.mul_add(bytecode.get_multiplies(index))?,
+        Instruction::Scan(stride) => machine.scan(stride, fuel)?,
+        Instruction::JumpIfZero(target) => {
+            if machine.get_byte()? == 0 {
+                return Ok(target);
+            }
+        }
+        Instruction::MoveJumpUnlessZero(by, target) => {
+            if by < 0 {
+                machine.decrement(by.unsigned_abs());
+            } else {
+                machine.increment(by as usize);
+            }
+            if machine.get_byte()? != 0 {
+                return Ok(target);
+            }
+        }
+        Instruction::JumpUnlessZero(target) => {
+            if machine.get_byte()? != 0 {
+                return Ok(target);
+            }
+        }
+    }
+    Ok(pc + 1)
+}
+
+/// Blame the token at `location` for stopping the machine.
+pub(crate) fn stopped(
+    trap: Trap,
+    location: (u32, u32),
+    limits: &Limits,
+) -> Result<ExecOutcome, BfcError> {
+    match trap {
+        Trap::OffTape => {
+            let (line, column) = location;
+            Err(BfcError::OffTape { line, column })
+        }
+        Trap::TapeLimit => Ok(ExecOutcome::TapeExhausted {
+            cells: limits.tape.unwrap_or(usize::MAX),
+            location,
+        }),
+        Trap::OutputLimit => Ok(ExecOutcome::OutputExhausted {
+            bytes: limits.output.unwrap_or(u64::MAX),
+            location,
+        }),
+        Trap::OutOfFuel => Ok(ExecOutcome::FuelExhausted {
+            steps: limits.steps.unwrap_or(u64::MAX),
+            location,
+        }),
+        // However many steps there are left, they'd all get used up going around
+        Trap::Endless => Ok(match limits.steps {
+            Some(steps) => ExecOutcome::FuelExhausted { steps, location },
+            None => ExecOutcome::Endless { location },
+        }),
+        Trap::Input(source) => Err(BfcError::io("Could not read input")(source)),
+        Trap::Output(source) => Err(BfcError::io("Could not write output")(source)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::execute::machine::{EofPolicy, IoMode, Limits, TapePolicy};
+    use crate::parse::ast::{generate_ast, MAX_NESTING};
+    use crate::parse::opcodes::OpCodes;
+    use crate::parse::optimizer::{level, optimize};
+    use crate::{Options, Program};
+
+    fn run(program: &str, input: &[u8], eof: EofPolicy) -> Vec<u8> {
+        run_with::<u8>(program, input, eof, IoMode::Bytes)
+    }
+
+    fn run_with<C: Cell>(program: &str, input: &[u8], eof: EofPolicy, io: IoMode) -> Vec<u8> {
+        let mut machine = Machine::<C, _, _>::in_memory(30_000, TapePolicy::Wrap, eof, io, input);
+        interpret(&generate_ast(&mut program.chars()).unwrap(), &mut machine).unwrap();
+        machine.into_writer()
+    }
+
+    #[test]
+    fn in_memory_io() {
+        assert_eq!(run(",[.,]", b"cat", EofPolicy::Zero), b"cat");
+        assert_eq!(run(",+.", b"", EofPolicy::Zero), [1]);
+        assert_eq!(run("+++,.", b"", EofPolicy::Unchanged), [3]);
+        assert_eq!(run(",+[-.,+]", b"ab", EofPolicy::MinusOne), b"ab");
+    }
+
+    #[test]
+    fn io_modes() {
+        let two_hundred = "++++++++++[>++++++++++<-]>[<++>-]<.";
+        assert_eq!(run(two_hundred, b"", EofPolicy::Zero), [200]);
+        assert_eq!(
+            run_with::<u32>(two_hundred, b"", EofPolicy::Zero, IoMode::Bytes),
+            [200]
+        );
+        assert_eq!(
+            run_with::<u32>(two_hundred, b"", EofPolicy::Zero, IoMode::Utf8),
+            "\u{c8}".as_bytes()
+        );
+        // Way past U+10FFFF
+        assert_eq!(
+            run_with::<u32>("-.", b"", EofPolicy::Zero, IoMode::Utf8),
+            "\u{fffd}".as_bytes()
+        );
+        let text = "h\u{e9}\u{20ac}\u{1f600}";
+        assert_eq!(
+            run_with::<u32>(",[.,]", text.as_bytes(), EofPolicy::Zero, IoMode::Utf8),
+            text.as_bytes()
+        );
+        // A stray continuation byte, and a sequence that gets cut short by the `h`
+        assert_eq!(
+            run_with::<u32>(",[.,]", b"\x80\xe2\x82h", EofPolicy::Zero, IoMode::Utf8),
+            "\u{fffd}\u{fffd}".as_bytes()
+        );
+    }
+
+    #[test]
+    fn deep_nesting() {
+        // +[[[...[.[-]][-]]...[-]], with `depth` loops inside each other counting the `[-]`
+        let nested = |depth: usize| {
+            let outer = "[".repeat(depth - 1);
+            format!("+{outer}.[-]{}", "][-]".repeat(depth - 1))
+        };
+        let options = Options::default();
+        let program = Program::parse(&nested(MAX_NESTING)).unwrap();
+        for level in [0, 3] {
+            let optimized = program.clone().optimize(level, &options);
+            let (output, _) = optimized.run(&options, &[][..], vec![]).unwrap();
+            assert_eq!(output, [1]);
+        }
+        match Program::parse(&nested(MAX_NESTING + 1)) {
+            Err(BfcError::Parse { unmatched, report }) => {
+                assert!(unmatched.is_empty());
+                assert!(report.starts_with("Loops nested more than 1000 deep at 1:1003\n"));
+            }
+            other => panic!("{other:?}"),
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "The tape needs at least one cell")]
+    fn empty_tape() {
+        Machine::<u8, _, _>::in_memory(0, TapePolicy::Wrap, EofPolicy::Zero, IoMode::Bytes, []);
+    }
+
+    #[test]
+    fn limits() {
+        let limited = |program: &str, policy, limits| {
+            let mut machine =
+                Machine::<u8, _, _>::in_memory(4, policy, EofPolicy::Zero, IoMode::Utf8, []);
+            machine.set_limits(limits);
+            let outcome = interpret(&generate_ast(&mut program.chars()).unwrap(), &mut machine);
+            (outcome.unwrap(), machine.into_writer())
+        };
+        let steps = Limits {
+            steps: Some(10),
+            ..Limits::default()
+        };
+        assert_eq!(
+            limited("+[]", TapePolicy::Wrap, steps),
+            (
+                ExecOutcome::FuelExhausted {
+                    steps: 10,
+                    location: (1, 2)
+                },
+                vec![]
+            )
+        );
+        assert_eq!(
+            limited("+++++.", TapePolicy::Wrap, steps),
+            (ExecOutcome::Finished, vec![5])
+        );
+        let tape = Limits {
+            tape: Some(6),
+            ..Limits::default()
+        };
+        assert_eq!(
+            limited(">>>>>+", TapePolicy::GrowRight, tape).0,
+            ExecOutcome::Finished
+        );
+        assert_eq!(
+            limited(">>>>>>+", TapePolicy::GrowRight, tape).0,
+            ExecOutcome::TapeExhausted {
+                cells: 6,
+                location: (1, 7)
+            }
+        );
+        assert_eq!(
+            limited("<<+", TapePolicy::GrowBoth, tape).0,
+            ExecOutcome::Finished
+        );
+        assert_eq!(
+            limited("<<<+", TapePolicy::GrowBoth, tape).0,
+            ExecOutcome::TapeExhausted {
+                cells: 6,
+                location: (1, 4)
+            }
+        );
+        // U+0001 and then U+00C8 twice, which is two bytes every time
+        let output = Limits {
+            output: Some(4),
+            ..Limits::default()
+        };
+        assert_eq!(
+            limited(
+                "+.-++++++++++[>++++++++++<-]>[<++>-]<..",
+                TapePolicy::Wrap,
+                output
+            ),
+            (
+                ExecOutcome::OutputExhausted {
+                    bytes: 4,
+                    location: (1, 39)
+                },
+                vec![1, 0xc3, 0x88]
+            )
+        );
+    }
+
+    #[test]
+    fn scans() {
+        fn pointer<C: Cell>(program: &str) -> isize {
+            let ast = generate_ast(&mut program.chars()).unwrap();
+            let ast = optimize(&ast, &level(2), EofPolicy::Zero);
+            let mut machine = Machine::<C, _, _>::in_memory(
+                10,
+                TapePolicy::Wrap,
+                EofPolicy::Zero,
+                IoMode::Bytes,
+                [],
+            );
+            interpret(&ast, &mut machine).unwrap();
+            machine.get_pointer()
+        }
+        // Bytes get searched with memchr, so check they land where wider cells do, going round
+        // the end of the tape both ways
+        for (program, at) in [("+>+>+<<[>]", 3), ("+>+>+[<]", 9), ("<+<+[>]", 0)] {
+            assert_eq!(pointer::<u8>(program), at, "{program}");
+            assert_eq!(pointer::<u16>(program), at, "{program}");
+        }
+    }
+
+    #[test]
+    fn endless_scans() {
+        let scanned = |program: &str, steps| {
+            let ast = generate_ast(&mut program.chars()).unwrap();
+            let ast = optimize(&ast, &level(3), EofPolicy::Zero);
+            assert!(ast
+                .iter()
+                .any(|token| matches!(token.get_type(), OpCodes::Scan(_))));
+            let mut machine = Machine::<u8, _, _>::in_memory(
+                10,
+                TapePolicy::Wrap,
+                EofPolicy::Zero,
+                IoMode::Bytes,
+                [],
+            );
+            machine.set_limits(Limits {
+                steps,
+                ..Limits::default()
+            });
+            interpret(&ast, &mut machine).unwrap()
+        };
+        // Every cell ends up nonzero before the last loop goes looking for a zero
+        for program in ["->+[>+]+[>]", "->+[>+]+[>>]", "->+[>+]+[<<<]"] {
+            let location = (1, program.rfind('[').unwrap() as u32 + 1);
+            assert_eq!(scanned(program, None), ExecOutcome::Endless { location });
+            assert_eq!(
+                scanned(program, Some(1_000)),
+                ExecOutcome::FuelExhausted {
+                    steps: 1_000,
+                    location
+                }
+            );
+        }
+        // Four cells set, then the scan and each of its four moves is a step
+        let wide = "+>>+>>+>>+<<<<<<[>>]";
+        assert_eq!(scanned(wide, Some(9)), ExecOutcome::Finished);
+        assert_eq!(
+            scanned(wide, Some(8)),
+            ExecOutcome::FuelExhausted {
+                steps: 8,
+                location: (1, 17)
+            }
+        );
     }
 }
@@ -0,0 +1,134 @@
+//! Running bytecode as native code, for the machines we know how to write it for.
+
+#[cfg(all(target_arch = "x86_64", unix))]
+mod x86_64;
+
+use crate::error::BfcError;
+use crate::execute::bytecode::Bytecode;
+use crate::execute::interpret::ExecOutcome;
+use crate::execute::machine::{Cell, Machine};
+use std::io::{Read, Write};
+
+/// Whether `run` actually compiles anything here, or just interprets.
+pub const AVAILABLE: bool = cfg!(all(target_arch = "x86_64", unix));
+
+/// Compile `bytecode` to machine code and run it on the machine, exactly like
+/// `interpret::run` would. Anywhere without a JIT it gets interpreted.
+pub fn run<C: Cell, R: Read, W: Write>(
+    bytecode: &Bytecode,
+    machine: &mut Machine<C, R, W>,
+) -> Result<ExecOutcome, BfcError> {
+    #[cfg(all(target_arch = "x86_64", unix))]
+    return x86_64::run(bytecode, machine);
+    #[cfg(not(all(target_arch = "x86_64", unix)))]
+    return crate::execute::interpret::run(bytecode, machine);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::execute::interpret;
+    use crate::execute::machine::{EofPolicy, IoMode, Limits, TapePolicy};
+    use crate::parse::ast::generate_ast;
+    use crate::parse::optimizer::{level, optimize};
+
+    /// Run `program` with the JIT and the interpreter, which should agree on the output, the
+    /// tape and the pointer, or on what stopped them.
+    fn both<C: Cell>(program: &str, input: &[u8], size: usize, policy: TapePolicy, limits: Limits) {
+        let ast = generate_ast(&mut program.chars()).unwrap();
+        let ast = optimize(&ast, &level(3), EofPolicy::Zero);
+        let bytecode = Bytecode::lower(&ast);
+        let [jitted, interpreted] = [true, false].map(|jit| {
+            let mut machine =
+                Machine::<C, _, _>::in_memory(size, policy, EofPolicy::Zero, IoMode::Bytes, input);
+            machine.set_limits(limits);
+            let result = if jit {
+                run(&bytecode, &mut machine)
+            } else {
+                interpret::run(&bytecode, &mut machine)
+            };
+            (
+                result.map_err(|error| error.to_string()),
+                machine.get_pointer(),
+                machine.get_tape().to_vec(),
+                machine.into_writer(),
+            )
+        });
+        assert_eq!(jitted, interpreted, "{program}");
+    }
+
+    #[test]
+    fn same_as_interpreting() {
+        let programs = [
+            ",[.,]",
+            "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.",
+            "+[>+>++<<-]>[-]>[>+<-]",
+            "->>->+[<]",
+            ">>>+++[<<<+>>>-]<<<[>>>>>>>>+<<<<<<<<-]",
+        ];
+        for program in programs {
+            both::<u8>(program, b"jit", 30_000, TapePolicy::Wrap, Limits::default());
+            both::<u16>(program, b"jit", 7, TapePolicy::Wrap, Limits::default());
+            both::<u32>(program, b"jit", 5, TapePolicy::GrowBoth, Limits::default());
+        }
+    }
+
+    #[test]
+    fn off_tape() {
+        both::<u8>("+[>+]", b"", 10, TapePolicy::Abort, Limits::default());
+        both::<u8>("<+", b"", 10, TapePolicy::GrowRight, Limits::default());
+        both::<u32>(
+            "+>>>>>>>+<<<<<<<<<+",
+            b"",
+            4,
+            TapePolicy::GrowBoth,
+            Limits::default(),
+        );
+    }
+
+    #[test]
+    fn limits() {
+        for steps in [0, 1, 7, 100] {
+            let limits = Limits {
+                steps: Some(steps),
+                ..Limits::default()
+            };
+            both::<u8>("+[>+<-]>[.-]+[]", b"", 10, TapePolicy::Wrap, limits);
+        }
+        let limits = Limits {
+            tape: Some(12),
+            output: Some(3),
+            ..Limits::default()
+        };
+        both::<u8>("+[>+]", b"", 10, TapePolicy::GrowRight, limits);
+        both::<u8>("+[<+]", b"", 10, TapePolicy::GrowBoth, limits);
+        both::<u8>("+[.]", b"", 10, TapePolicy::Wrap, limits);
+    }
+
+    #[test]
+    fn endless_scans() {
+        let programs = [
+            "->+[>+]+[>]",
+            "->+[>+]+[>>]",
+            "->+[>+]+[<<<]",
+            "+>>+>>+>>+[>>]",
+            "+>>>+<<<<<<+[<<<]",
+        ];
+        for program in programs {
+            for steps in [None, Some(20), Some(21), Some(22), Some(23), Some(1_000)] {
+                let limits = Limits {
+                    steps,
+                    ..Limits::default()
+                };
+                both::<u8>(program, b"", 10, TapePolicy::Wrap, limits);
+                both::<u16>(program, b"", 12, TapePolicy::Wrap, limits);
+            }
+            let limits = Limits {
+                steps: Some(1_000),
+                ..Limits::default()
+            };
+            both::<u8>(program, b"", 10, TapePolicy::GrowBoth, limits);
+            both::<u8>(program, b"", 10, TapePolicy::Abort, limits);
+        }
+    }
+}
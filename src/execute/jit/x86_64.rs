@@ -0,0 +1,594 @@
+use crate::error::BfcError;
+use crate::execute::bytecode::{Bytecode, Instruction};
+use crate::execute::interpret::{self, stopped, ExecOutcome};
+use crate::execute::machine::{Cell, Machine, TapePolicy, Trap};
+use std::ffi::c_void;
+use std::io::{self, Read, Write};
+use std::{mem, ptr};
+
+// Registers, numbered the way instructions encode them
+const RAX: u8 = 0;
+const RCX: u8 = 1;
+const RDX: u8 = 2;
+const RBX: u8 = 3;
+const RBP: u8 = 5;
+const RSI: u8 = 6;
+const RDI: u8 = 7;
+const R12: u8 = 12;
+const R13: u8 = 13;
+const R14: u8 = 14;
+const R15: u8 = 15;
+
+// Where the generated code keeps things. All of them are callee saved, so calling back into Rust
+// leaves them alone.
+/// Start of the tape
+const TAPE: u8 = RBX;
+/// The pointer, as an index into the tape
+const PTR: u8 = R12;
+/// How many cells the tape has
+const LEN: u8 = R13;
+/// The `Context`
+const CONTEXT: u8 = R14;
+/// The current cell while a `MulAdd` spreads it around
+const VALUE: u8 = R15;
+/// Steps left, when there's a limit on them
+const FUEL: u8 = RBP;
+
+// Condition codes for `jcc`
+const BELOW: u8 = 0x2;
+const NOT_BELOW: u8 = 0x3;
+const ZERO: u8 = 0x4;
+const NOT_ZERO: u8 = 0x5;
+
+/// What the generated code and the functions it calls back into share. The code reads and
+/// writes the first four fields directly, at `TAPE_FIELD`, `LEN_FIELD`, `PTR_FIELD` and
+/// `FUEL_FIELD`.
+#[repr(C)]
+struct Context<C: Cell, R: Read, W: Write> {
+    tape: *mut C,
+    len: usize,
+    ptr: isize,
+    fuel: u64,
+    machine: *mut Machine<C, R, W>,
+    trap: Option<Trap>,
+}
+
+const TAPE_FIELD: u8 = 0;
+const LEN_FIELD: u8 = 8;
+const PTR_FIELD: u8 = 16;
+const FUEL_FIELD: u8 = 24;
+
+impl<C: Cell, R: Read, W: Write> Context<C, R, W> {
+    /// Pick up the tape and the pointer, which the machine may have moved.
+    fn load(&mut self) {
+        // SAFETY: the machine outlives the context, and only one of them is used at a time
+        let machine = unsafe { &mut *self.machine };
+        self.ptr = machine.get_pointer();
+        let tape = machine.get_tape_mut();
+        self.len = tape.len();
+        self.tape = tape.as_mut_ptr();
+    }
+
+    /// Hand the pointer back to the machine for `op`, then pick up whatever it left behind.
+    /// Gives back 0 to carry on, or 1 once it's stopped the machine.
+    fn call(&mut self, op: impl FnOnce(&mut Machine<C, R, W>) -> Result<(), Trap>) -> u32 {
+        // SAFETY: same as in `load`
+        let machine = unsafe { &mut *self.machine };
+        machine.set_pointer(self.ptr);
+        let result = op(machine);
+        self.load();
+        match result {
+            Ok(()) => 0,
+            Err(trap) => {
+                self.trap = Some(trap);
+                1
+            }
+        }
+    }
+}
+
+// What the generated code calls for `,`, `.`, and cells that are off the tape. They all take the
+// context and an offset from the pointer.
+
+extern "C" fn input<C: Cell, R: Read, W: Write>(
+    context: *mut Context<C, R, W>,
+    offset: i32,
+) -> u32 {
+    // SAFETY: the generated code only ever passes along the context it was started with
+    unsafe { &mut *context }.call(|machine| machine.input(offset))
+}
+
+extern "C" fn output<C: Cell, R: Read, W: Write>(
+    context: *mut Context<C, R, W>,
+    offset: i32,
+) -> u32 {
+    // SAFETY: see `input`
+    unsafe { &mut *context }.call(|machine| machine.output(offset))
+}
+
+/// Let the tape policy deal with a cell that isn't on the tape.
+extern "C" fn off_tape<C: Cell, R: Read, W: Write>(
+    context: *mut Context<C, R, W>,
+    offset: i32,
+) -> u32 {
+    // SAFETY: see `input`
+    unsafe { &mut *context }.call(|machine| machine.cell(offset).map(|_| ()))
+}
+
+/// Stop a scan that went all the way around the tape without finding a zero.
+extern "C" fn endless<C: Cell, R: Read, W: Write>(
+    context: *mut Context<C, R, W>,
+    _offset: i32,
+) -> u32 {
+    // SAFETY: see `input`
+    unsafe { &mut *context }.call(|_| Err(Trap::Endless))
+}
+
+/// Compile `bytecode` for this machine and run it.
+pub fn run<C: Cell, R: Read, W: Write>(
+    bytecode: &Bytecode,
+    machine: &mut Machine<C, R, W>,
+) -> Result<ExecOutcome, BfcError> {
+    let wraps = machine.get_policy() == TapePolicy::Wrap;
+    // Offsets become forwards ones of less than a whole tape, which have to fit in an instruction
+    if wraps && machine.get_size() > i32::MAX as usize {
+        return interpret::run(bytecode, machine);
+    }
+    let limits = machine.get_limits();
+    let mut assembler = Assembler {
+        code: Vec::new(),
+        fuel: limits.steps.is_some(),
+        width: mem::size_of::<C>(),
+        wraps,
+        size: machine.get_size() as i64,
+        callbacks: [
+            input::<C, R, W> as *const () as u64,
+            output::<C, R, W> as *const () as u64,
+            off_tape::<C, R, W> as *const () as u64,
+            endless::<C, R, W> as *const () as u64,
+        ],
+        exits: Vec::new(),
+    };
+    let code = assembler.assemble(bytecode);
+    let executable = Executable::new(&code).map_err(BfcError::io("Could not set up the JIT"))?;
+    let mut context = Context {
+        tape: ptr::null_mut(),
+        len: 0,
+        ptr: 0,
+        fuel: limits.steps.unwrap_or(0),
+        machine: machine as *mut _,
+        trap: None,
+    };
+    context.load();
+    // SAFETY: the code was generated for exactly this kind of context, and follows the C calling
+    // convention
+    let entry: extern "C" fn(*mut Context<C, R, W>) -> u64 =
+        unsafe { mem::transmute(executable.memory) };
+    let stopped_at = entry(&mut context);
+    machine.set_pointer(context.ptr);
+    let Some(pc) = stopped_at.checked_sub(1) else {
+        return Ok(ExecOutcome::Finished);
+    };
+    let location = bytecode.get_location(pc as usize);
+    match context.trap {
+        Some(trap) => stopped(trap, location, &limits),
+        // Leaving without a trap is running out of steps
+        None => Ok(ExecOutcome::FuelExhausted {
+            steps: limits.steps.unwrap_or(u64::MAX),
+            location,
+        }),
+    }
+}
+
+/// Writes the machine code. The generated function takes the `Context` and gives back 0 once
+/// the program is done, or one more than the instruction that stopped the machine or ran out of
+/// steps.
+struct Assembler {
+    code: Vec<u8>,
+    /// Whether to count steps, see `FUEL`
+    fuel: bool,
+    /// Bytes per cell
+    width: usize,
+    wraps: bool,
+    /// Cells on the tape, which only stays that way when wrapping
+    size: i64,
+    /// `input`, `output`, `off_tape` and `endless`
+    callbacks: [u64; 4],
+    /// Jumps that leave the function
+    exits: Vec<usize>,
+}
+
+impl Assembler {
+    fn assemble(&mut self, bytecode: &Bytecode) -> Vec<u8> {
+        for reg in [RBP, RBX, R12, R13, R14, R15] {
+            self.push(reg);
+        }
+        // Six pushes and the return address leave the stack 8 bytes off for calls
+        self.bytes(&[0x48, 0x83, 0xec, 0x08]);
+        self.reg_reg(true, &[0x89], RDI, CONTEXT);
+        self.load_tape();
+        if self.fuel {
+            self.load_field(FUEL, FUEL_FIELD);
+        }
+        // Where every instruction starts, and where the program ends
+        let mut starts = Vec::new();
+        // Jumps to fill in once everything has a place, as (rel32, instruction)
+        let mut jumps = Vec::new();
+        for (pc, instruction) in bytecode.get_code().iter().enumerate() {
+            starts.push(self.code.len());
+            if self.fuel {
+                self.charge(pc);
+            }
+            match *instruction {
+                Instruction::Add(x, offset) => {
+                    self.cell(offset, pc);
+                    self.load();
+                    // add ecx, x
+                    self.bytes(&[0x81, 0xc1]);
+                    self.imm32(x);
+                    self.store();
+                }
+                Instruction::Sub(x, offset) => {
+                    self.cell(offset, pc);
+                    self.load();
+                    // sub ecx, x
+                    self.bytes(&[0x81, 0xe9]);
+                    self.imm32(x);
+                    self.store();
+                }
+                Instruction::Inc(x) => self.shift(x.into()),
+                Instruction::Dec(x) => self.shift(-i64::from(x)),
+                Instruction::Clear(offset) => self.set(0, offset, pc),
+                Instruction::Set(x, offset) => self.set(x, offset, pc),
+                Instruction::Output(offset) => self.call(self.callbacks[1], offset, pc),
+                Instruction::Input(offset) => self.call(self.callbacks[0], offset, pc),
+                Instruction::MulAdd(index) => {
+                    self.cell(0, pc);
+                    self.load();
+                    self.reg_reg(false, &[0x89], RCX, VALUE);
+                    self.reg_reg(false, &[0x85], VALUE, VALUE);
+                    let skip = self.jump_if(ZERO);
+                    for &(offset, factor) in bytecode.get_multiplies(index) {
+                        self.cell(offset, pc);
+                        self.load();
+                        // imul edx, r15d, factor
+                        self.rex(false, RDX, VALUE);
+                        self.bytes(&[0x69, 0xc0 | (RDX & 7) << 3 | VALUE & 7]);
+                        self.imm32(factor as u32);
+                        self.reg_reg(false, &[0x01], RDX, RCX);
+                        self.store();
+                    }
+                    self.set(0, 0, pc);
+                    self.land(skip);
+                }
+                Instruction::Scan(stride) => {
+                    // Moves so far go in rdx when wrapping, which nothing in the loop touches
+                    if self.wraps {
+                        // xor edx, edx
+                        self.bytes(&[0x31, 0xd2]);
+                    }
+                    let top = self.code.len();
+                    self.current_is_zero(pc);
+                    let found = self.jump_if(ZERO);
+                    if self.wraps {
+                        // cmp rdx, r13: all the way around is as far as it goes
+                        self.reg_reg(true, &[0x39], LEN, RDX);
+                        let more = self.jump_if(BELOW);
+                        self.call(self.callbacks[3], 0, pc);
+                        self.land(more);
+                    }
+                    // Same as the interpreter, only bigger strides pay for every move
+                    if self.fuel && stride.abs() != 1 {
+                        self.charge(pc);
+                    }
+                    self.shift(stride.into());
+                    if self.wraps {
+                        // add rdx, 1
+                        self.rex(true, 0, RDX);
+                        self.bytes(&[0x83, 0xc0 | RDX & 7, 0x01]);
+                    }
+                    let back = self.jump();
+                    self.patch(back, top);
+                    self.land(found);
+                }
+                Instruction::JumpIfZero(target) => {
+                    self.current_is_zero(pc);
+                    jumps.push((self.jump_if(ZERO), target));
+                }
+                Instruction::JumpUnlessZero(target) => {
+                    self.current_is_zero(pc);
+                    jumps.push((self.jump_if(NOT_ZERO), target));
+                }
+                Instruction::MoveJumpUnlessZero(by, target) => {
+                    self.shift(by as i64);
+                    self.current_is_zero(pc);
+                    jumps.push((self.jump_if(NOT_ZERO), target));
+                }
+            }
+        }
+        starts.push(self.code.len());
+        for (at, target) in jumps {
+            self.patch(at, starts[target]);
+        }
+        // xor eax, eax
+        self.bytes(&[0x31, 0xc0]);
+        for at in mem::take(&mut self.exits) {
+            self.land(at);
+        }
+        self.store_field(PTR, PTR_FIELD);
+        self.bytes(&[0x48, 0x83, 0xc4, 0x08]);
+        for reg in [R15, R14, R13, R12, RBX, RBP] {
+            self.pop(reg);
+        }
+        // ret
+        self.byte(0xc3);
+        mem::take(&mut self.code)
+    }
+
+    /// Put the index of the cell `offset` away from the pointer in rax, bringing it back around
+    /// when wrapping or letting the tape policy have a go at it otherwise.
+    fn cell(&mut self, offset: i32, pc: usize) {
+        if self.wraps {
+            let offset = i64::from(offset).rem_euclid(self.size);
+            if offset == 0 {
+                self.reg_reg(true, &[0x89], PTR, RAX);
+            } else {
+                self.pointer_plus(offset as i32);
+                self.wrap(RAX);
+            }
+        } else {
+            let retry = self.code.len();
+            self.pointer_plus(offset);
+            // cmp rax, r13, where anything left of the tape looks huge too
+            self.reg_reg(true, &[0x39], LEN, RAX);
+            let on_tape = self.jump_if(BELOW);
+            self.call(self.callbacks[2], offset, pc);
+            let back = self.jump();
+            self.patch(back, retry);
+            self.land(on_tape);
+        }
+    }
+
+    /// Take a step off the fuel, leaving the function if there wasn't one left.
+    fn charge(&mut self, pc: usize) {
+        // sub rbp, 1, which only borrows once there's nothing left
+        self.rex(true, 0, FUEL);
+        self.bytes(&[0x83, 0xe8 | FUEL & 7, 0x01]);
+        let carry_on = self.jump_if(NOT_BELOW);
+        self.leave(pc);
+        self.land(carry_on);
+    }
+
+    /// Whether the current cell is 0, in the zero flag.
+    fn current_is_zero(&mut self, pc: usize) {
+        self.cell(0, pc);
+        self.load();
+        self.reg_reg(false, &[0x85], RCX, RCX);
+    }
+
+    fn set(&mut self, x: u32, offset: i32, pc: usize) {
+        self.cell(offset, pc);
+        // mov ecx, x
+        self.byte(0xb9);
+        self.imm32(x);
+        self.store();
+    }
+
+    /// Move the pointer `by` cells.
+    fn shift(&mut self, by: i64) {
+        let by = if self.wraps {
+            by.rem_euclid(self.size)
+        } else {
+            by
+        };
+        if by == 0 {
+            return;
+        }
+        self.mov_imm(RCX, by as u64);
+        self.reg_reg(true, &[0x01], RCX, PTR);
+        if self.wraps {
+            self.wrap(PTR);
+        }
+    }
+
+    /// Bring an index less than a whole tape past its end back around, without branching.
+    fn wrap(&mut self, reg: u8) {
+        self.reg_reg(true, &[0x89], reg, RCX);
+        self.reg_reg(true, &[0x29], LEN, RCX);
+        self.reg_reg(true, &[0x39], LEN, reg);
+        // cmovae reg, rcx
+        self.reg_reg(true, &[0x0f, 0x43], reg, RCX);
+    }
+
+    /// Call back into Rust with the context and `offset`, leaving the function if that stopped
+    /// the machine.
+    fn call(&mut self, function: u64, offset: i32, pc: usize) {
+        self.store_field(PTR, PTR_FIELD);
+        self.reg_reg(true, &[0x89], CONTEXT, RDI);
+        // mov esi, offset
+        self.byte(0xb8 | RSI);
+        self.imm32(offset as u32);
+        self.mov_imm(RAX, function);
+        // call rax
+        self.bytes(&[0xff, 0xd0]);
+        self.load_tape();
+        self.reg_reg(false, &[0x85], RAX, RAX);
+        let carry_on = self.jump_if(ZERO);
+        self.leave(pc);
+        self.land(carry_on);
+    }
+
+    /// Leave the function, blaming instruction `pc`.
+    fn leave(&mut self, pc: usize) {
+        self.mov_imm(RAX, pc as u64 + 1);
+        let out = self.jump();
+        self.exits.push(out);
+    }
+
+    fn load_tape(&mut self) {
+        self.load_field(TAPE, TAPE_FIELD);
+        self.load_field(LEN, LEN_FIELD);
+        self.load_field(PTR, PTR_FIELD);
+    }
+
+    /// ecx = the cell at rax
+    fn load(&mut self) {
+        match self.width {
+            // movzx ecx, byte/word
+            1 => self.cell_operand(&[0x0f, 0xb6]),
+            2 => self.cell_operand(&[0x0f, 0xb7]),
+            // mov ecx, dword
+            _ => self.cell_operand(&[0x8b]),
+        }
+    }
+
+    /// The cell at rax = as much of ecx as fits
+    fn store(&mut self) {
+        match self.width {
+            1 => self.cell_operand(&[0x88]),
+            2 => self.cell_operand(&[0x66, 0x89]),
+            _ => self.cell_operand(&[0x89]),
+        }
+    }
+
+    /// `opcode` between ecx and [rbx + rax*width].
+    fn cell_operand(&mut self, opcode: &[u8]) {
+        let scale = self.width.trailing_zeros() as u8;
+        self.bytes(opcode);
+        self.bytes(&[(RCX & 7) << 3 | 0b100, scale << 6 | RAX << 3 | TAPE]);
+    }
+
+    /// lea rax, [r12 + disp]
+    fn pointer_plus(&mut self, disp: i32) {
+        self.rex(true, RAX, PTR);
+        // r12 as a base always needs a SIB byte
+        self.bytes(&[0x8d, 0x80 | (RAX & 7) << 3 | 0b100, 0x24]);
+        self.imm32(disp as u32);
+    }
+
+    /// mov reg, [r14 + field]
+    fn load_field(&mut self, reg: u8, field: u8) {
+        self.rex(true, reg, CONTEXT);
+        self.bytes(&[0x8b, 0x40 | (reg & 7) << 3 | CONTEXT & 7, field]);
+    }
+
+    /// mov [r14 + field], reg
+    fn store_field(&mut self, reg: u8, field: u8) {
+        self.rex(true, reg, CONTEXT);
+        self.bytes(&[0x89, 0x40 | (reg & 7) << 3 | CONTEXT & 7, field]);
+    }
+
+    fn mov_imm(&mut self, reg: u8, imm: u64) {
+        self.rex(true, 0, reg);
+        self.byte(0xb8 | reg & 7);
+        self.bytes(&imm.to_le_bytes());
+    }
+
+    fn push(&mut self, reg: u8) {
+        self.rex(false, 0, reg);
+        self.byte(0x50 | reg & 7);
+    }
+
+    fn pop(&mut self, reg: u8) {
+        self.rex(false, 0, reg);
+        self.byte(0x58 | reg & 7);
+    }
+
+    /// `opcode` with `reg` in the middle of the ModRM byte and `rm` (usually the destination) at
+    /// the end.
+    fn reg_reg(&mut self, wide: bool, opcode: &[u8], reg: u8, rm: u8) {
+        self.rex(wide, reg, rm);
+        self.bytes(opcode);
+        self.byte(0xc0 | (reg & 7) << 3 | rm & 7);
+    }
+
+    /// The REX prefix, left out when it wouldn't say anything.
+    fn rex(&mut self, wide: bool, reg: u8, rm: u8) {
+        let rex = 0x40 | u8::from(wide) << 3 | (reg >> 3) << 2 | rm >> 3;
+        if rex != 0x40 {
+            self.byte(rex);
+        }
+    }
+
+    /// jcc with the target filled in later, see `patch` and `land`.
+    fn jump_if(&mut self, condition: u8) -> usize {
+        self.bytes(&[0x0f, 0x80 | condition]);
+        self.imm32(0);
+        self.code.len() - 4
+    }
+
+    /// jmp with the target filled in later.
+    fn jump(&mut self) -> usize {
+        self.byte(0xe9);
+        self.imm32(0);
+        self.code.len() - 4
+    }
+
+    /// Point the jump whose rel32 is `at` to `target`.
+    fn patch(&mut self, at: usize, target: usize) {
+        let relative = target as i64 - (at as i64 + 4);
+        self.code[at..at + 4].copy_from_slice(&(relative as i32).to_le_bytes());
+    }
+
+    /// Point the jump whose rel32 is `at` to whatever comes next.
+    fn land(&mut self, at: usize) {
+        self.patch(at, self.code.len());
+    }
+
+    fn imm32(&mut self, imm: u32) {
+        self.bytes(&imm.to_le_bytes());
+    }
+
+    fn byte(&mut self, byte: u8) {
+        self.code.push(byte);
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+}
+
+/// Memory holding machine code that can be run, unmapped once dropped.
+struct Executable {
+    memory: *mut c_void,
+    size: usize,
+}
+
+impl Executable {
+    fn new(code: &[u8]) -> io::Result<Self> {
+        let size = code.len();
+        // SAFETY: asking for fresh memory doesn't touch any we already have
+        let memory = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        if memory == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        let executable = Executable { memory, size };
+        // SAFETY: the mapping is writable and exactly `size` bytes long. It only becomes
+        // executable after that, and never both at once
+        unsafe {
+            ptr::copy_nonoverlapping(code.as_ptr(), memory.cast(), size);
+            if libc::mprotect(memory, size, libc::PROT_READ | libc::PROT_EXEC) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(executable)
+    }
+}
+
+impl Drop for Executable {
+    fn drop(&mut self) {
+        // SAFETY: nothing runs the code anymore once the executable is gone
+        unsafe {
+            libc::munmap(self.memory, self.size);
+        }
+    }
+}
@@ -1,58 +1,522 @@
-use std::io::{self, stdin, Read, Write};
-use wrapnum::{wrap, WrapNum};
+use std::fmt::Debug;
+use std::io::{self, stdin, stdout, Cursor, Read, Stdin, Stdout, Write};
 
-pub struct Machine {
-    ptr: WrapNum<usize>,
-    tape: Vec<u32>,
+/// How many bits a cell holds before wrapping around.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CellWidth {
+    Eight,
+    Sixteen,
+    ThirtyTwo,
 }
 
-impl Machine {
-    pub fn new(size: usize) -> Self {
+impl CellWidth {
+    pub fn bytes(&self) -> u64 {
+        match self {
+            CellWidth::Eight => 1,
+            CellWidth::Sixteen => 2,
+            CellWidth::ThirtyTwo => 4,
+        }
+    }
+}
+
+/// Something that can sit on the tape. Amounts come in as `u32` and get cut down to the cell's
+/// width, so everything wraps exactly like the compiled program's loads and stores do.
+pub trait Cell: Copy + Default + PartialEq + Debug {
+    const WIDTH: CellWidth;
+
+    fn from_u32(value: u32) -> Self;
+    fn to_u32(self) -> u32;
+    fn wrapping_add(self, other: Self) -> Self;
+    fn wrapping_sub(self, other: Self) -> Self;
+    fn wrapping_mul(self, other: Self) -> Self;
+
+    /// Where the first zero in `cells` is.
+    fn find_zero(cells: &[Self]) -> Option<usize> {
+        cells.iter().position(|&cell| cell == Self::default())
+    }
+
+    /// Where the last zero in `cells` is.
+    fn rfind_zero(cells: &[Self]) -> Option<usize> {
+        cells.iter().rposition(|&cell| cell == Self::default())
+    }
+}
+
+macro_rules! impl_cell {
+    ($($cell:ty => $width:path $({ $($extra:item)* })?),*) => {
+        $(
+            impl Cell for $cell {
+                const WIDTH: CellWidth = $width;
+
+                fn from_u32(value: u32) -> Self {
+                    value as $cell
+                }
+
+                fn to_u32(self) -> u32 {
+                    self as u32
+                }
+
+                fn wrapping_add(self, other: Self) -> Self {
+                    <$cell>::wrapping_add(self, other)
+                }
+
+                fn wrapping_sub(self, other: Self) -> Self {
+                    <$cell>::wrapping_sub(self, other)
+                }
+
+                fn wrapping_mul(self, other: Self) -> Self {
+                    <$cell>::wrapping_mul(self, other)
+                }
+
+                $($($extra)*)?
+            }
+        )*
+    };
+}
+
+impl_cell!(
+    // Bytes are what memchr looks through, many at a time
+    u8 => CellWidth::Eight {
+        fn find_zero(cells: &[Self]) -> Option<usize> {
+            memchr::memchr(0, cells)
+        }
+
+        fn rfind_zero(cells: &[Self]) -> Option<usize> {
+            memchr::memrchr(0, cells)
+        }
+    },
+    u16 => CellWidth::Sixteen,
+    u32 => CellWidth::ThirtyTwo
+);
+
+/// What happens when the pointer walks off either end of the tape.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TapePolicy {
+    /// Come back around the other end.
+    Wrap,
+    /// Stop the program.
+    Abort,
+    /// Make the tape longer when going off the right, stop the program when going off the left.
+    GrowRight,
+    /// Make the tape longer on whichever end the pointer went off.
+    GrowBoth,
+}
+
+/// What `,` leaves in the cell once there's no more input.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EofPolicy {
+    /// Store 0.
+    Zero,
+    /// Store -1, which wraps around to the biggest value a cell can hold.
+    MinusOne,
+    /// Leave the cell alone.
+    Unchanged,
+}
+
+/// How `,` and `.` turn cells into input and output.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IoMode {
+    /// One byte per cell, cut down to its lowest 8 bits on the way out.
+    Bytes,
+    /// One UTF-8 encoded code point per cell. Cells that aren't one print as U+FFFD, and so does
+    /// input that isn't valid UTF-8.
+    Utf8,
+}
+
+/// How far a program gets to go before it's stopped, for running programs that can't be trusted
+/// to ever finish. `None` is no limit.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Limits {
+    /// Bytecode instructions run, see `execute::bytecode`
+    pub steps: Option<u64>,
+    /// Cells the tape can grow to
+    pub tape: Option<usize>,
+    /// Bytes written out
+    pub output: Option<u64>,
+}
+
+/// Why the machine can't carry on.
+#[derive(Debug)]
+pub enum Trap {
+    /// The pointer went off the tape and the policy says to stop.
+    OffTape,
+    /// Growing the tape would take it past `Limits::tape`.
+    TapeLimit,
+    /// Writing the cell would take the output past `Limits::output`.
+    OutputLimit,
+    /// A scan ran out of the steps it was given to move with, see `Machine::scan`.
+    OutOfFuel,
+    /// A scan on a wrapping tape went all the way around without finding a zero, so it never
+    /// would.
+    Endless,
+    /// Reading the input failed, for some other reason than running out of it.
+    Input(io::Error),
+    /// Writing the output failed.
+    Output(io::Error),
+}
+
+/// The tape, the pointer, and where `,` and `.` read from and write to. That's stdin and stdout
+/// unless the machine gets built with `with_io` or `in_memory`.
+pub struct Machine<C: Cell, R: Read = Stdin, W: Write = Stdout> {
+    /// Index into the tape. Always inside it when wrapping, otherwise it's allowed to wander off
+    /// as long as nothing gets read or written out there.
+    ptr: isize,
+    tape: Vec<C>,
+    policy: TapePolicy,
+    eof: EofPolicy,
+    io: IoMode,
+    limits: Limits,
+    /// Bytes written so far
+    written: u64,
+    reader: R,
+    writer: W,
+}
+
+impl<C: Cell> Machine<C> {
+    /// A machine on stdin and stdout. Panics if `size` is 0, see `with_io`.
+    pub fn new(size: usize, policy: TapePolicy, eof: EofPolicy, io: IoMode) -> Self {
+        Machine::with_io(size, policy, eof, io, stdin(), stdout())
+    }
+}
+
+impl<C: Cell> Machine<C, Cursor<Vec<u8>>, Vec<u8>> {
+    /// A machine that reads `input` and keeps everything it outputs, see `get_writer` and
+    /// `into_writer`. Panics if `size` is 0, see `with_io`.
+    pub fn in_memory(
+        size: usize,
+        policy: TapePolicy,
+        eof: EofPolicy,
+        io: IoMode,
+        input: impl Into<Vec<u8>>,
+    ) -> Self {
+        Machine::with_io(size, policy, eof, io, Cursor::new(input.into()), Vec::new())
+    }
+}
+
+impl<C: Cell, R: Read, W: Write> Machine<C, R, W> {
+    /// A machine with a tape of `size` cells, reading from `reader` and writing to `writer`.
+    ///
+    /// Panics if `size` is 0: there has to be a cell for the pointer to start on, and wrapping
+    /// or growing need a tape to go around or double.
+    pub fn with_io(
+        size: usize,
+        policy: TapePolicy,
+        eof: EofPolicy,
+        io: IoMode,
+        reader: R,
+        writer: W,
+    ) -> Self {
+        assert!(size > 0, "The tape needs at least one cell");
         Machine {
-            ptr: wrap!(size),
-            tape: vec![0; size],
+            ptr: 0,
+            tape: vec![C::default(); size],
+            policy,
+            eof,
+            io,
+            limits: Limits::default(),
+            written: 0,
+            reader,
+            writer,
         }
     }
 
+    pub fn get_reader(&self) -> &R {
+        &self.reader
+    }
+
+    pub fn get_reader_mut(&mut self) -> &mut R {
+        &mut self.reader
+    }
+
+    pub fn get_writer(&self) -> &W {
+        &self.writer
+    }
+
+    pub fn get_writer_mut(&mut self) -> &mut W {
+        &mut self.writer
+    }
+
+    pub fn into_writer(self) -> W {
+        self.writer
+    }
+
+    /// Where the pointer is on the tape. Only wrapping keeps it from going outside of it.
+    pub fn get_pointer(&self) -> isize {
+        self.ptr
+    }
+
+    /// Put the pointer somewhere else, back around onto the tape when wrapping.
+    pub fn set_pointer(&mut self, ptr: isize) {
+        self.ptr = 0;
+        self.shift(ptr);
+    }
+
+    pub fn get_tape(&self) -> &[C] {
+        &self.tape
+    }
+
+    pub fn get_tape_mut(&mut self) -> &mut [C] {
+        &mut self.tape
+    }
+
     pub fn get_size(&self) -> usize {
         self.tape.len()
     }
 
-    pub fn set_byte(&mut self, num: u32) {
-        self.tape[self.ptr] = num;
+    pub fn get_policy(&self) -> TapePolicy {
+        self.policy
     }
 
-    pub fn add(&mut self, num: u32) {
-        self.tape[self.ptr] = self.tape[self.ptr].wrapping_add(num);
+    pub fn get_eof(&self) -> EofPolicy {
+        self.eof
     }
 
-    pub fn sub(&mut self, num: u32) {
-        self.tape[self.ptr] = self.tape[self.ptr].wrapping_sub(num);
+    pub fn get_io_mode(&self) -> IoMode {
+        self.io
+    }
+
+    pub fn get_limits(&self) -> Limits {
+        self.limits
+    }
+
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    /// Where the cell `offset` away from the pointer is, growing the tape if the policy allows
+    /// it.
+    #[inline(always)]
+    pub(crate) fn cell(&mut self, offset: i32) -> Result<usize, Trap> {
+        // Going off the left end wraps around to something huge, so one comparison covers both
+        let at = self.ptr.wrapping_add(offset as isize) as usize;
+        if at < self.tape.len() {
+            Ok(at)
+        } else {
+            self.off_tape(offset)
+        }
+    }
+
+    /// Everything `cell` does once the pointer is off the tape.
+    #[cold]
+    #[inline(never)]
+    fn off_tape(&mut self, offset: i32) -> Result<usize, Trap> {
+        let at = self.ptr + offset as isize;
+        let size = self.tape.len() as isize;
+        match self.policy {
+            TapePolicy::Wrap => Ok(at.rem_euclid(size) as usize),
+            TapePolicy::GrowRight | TapePolicy::GrowBoth if at >= size => {
+                let limit = self.limits.tape.unwrap_or(usize::MAX);
+                if at as usize >= limit {
+                    return Err(Trap::TapeLimit);
+                }
+                // Double it so walking right one cell at a time doesn't reallocate every step
+                self.tape.resize(
+                    (at as usize + 1).max(self.tape.len() * 2).min(limit),
+                    C::default(),
+                );
+                Ok(at as usize)
+            }
+            TapePolicy::GrowBoth => {
+                let room = self
+                    .limits
+                    .tape
+                    .map_or(usize::MAX, |limit| limit.saturating_sub(self.tape.len()));
+                if at.unsigned_abs() > room {
+                    return Err(Trap::TapeLimit);
+                }
+                let extra = at.unsigned_abs().max(self.tape.len()).min(room);
+                let mut tape = vec![C::default(); extra];
+                tape.append(&mut self.tape);
+                self.tape = tape;
+                self.ptr += extra as isize;
+                Ok((at + extra as isize) as usize)
+            }
+            TapePolicy::Abort | TapePolicy::GrowRight => Err(Trap::OffTape),
+        }
+    }
+
+    /// Move the pointer, keeping it on the tape when wrapping.
+    #[inline(always)]
+    fn shift(&mut self, by: isize) {
+        self.ptr += by;
+        if self.ptr as usize >= self.tape.len() && self.policy == TapePolicy::Wrap {
+            self.ptr = self.ptr.rem_euclid(self.tape.len() as isize);
+        }
+    }
+
+    pub fn set_byte(&mut self, offset: i32, num: u32) -> Result<(), Trap> {
+        let at = self.cell(offset)?;
+        self.tape[at] = C::from_u32(num);
+        Ok(())
+    }
+
+    pub fn add(&mut self, offset: i32, num: u32) -> Result<(), Trap> {
+        let at = self.cell(offset)?;
+        self.tape[at] = self.tape[at].wrapping_add(C::from_u32(num));
+        Ok(())
+    }
+
+    pub fn sub(&mut self, offset: i32, num: u32) -> Result<(), Trap> {
+        let at = self.cell(offset)?;
+        self.tape[at] = self.tape[at].wrapping_sub(C::from_u32(num));
+        Ok(())
+    }
+
+    /// Add `factor` times the current cell to each offset cell, then clear the current cell.
+    /// Nothing happens when the current cell is already 0, just like the loop this came from.
+    pub fn mul_add(&mut self, targets: &[(i32, i32)]) -> Result<(), Trap> {
+        let here = self.cell(0)?;
+        let value = self.tape[here];
+        if value == C::default() {
+            return Ok(());
+        }
+        for (offset, factor) in targets {
+            let at = self.cell(*offset)?;
+            self.tape[at] =
+                self.tape[at].wrapping_add(value.wrapping_mul(C::from_u32(*factor as u32)));
+        }
+        // Growing on the left moves everything over, so look the current cell up again
+        let here = self.cell(0)?;
+        self.tape[here] = C::default();
+        Ok(())
     }
 
     pub fn increment(&mut self, num: usize) {
-        self.ptr += num;
+        self.shift(num as isize);
     }
 
     pub fn decrement(&mut self, num: usize) {
-        self.ptr -= num;
+        self.shift(-(num as isize));
     }
 
-    pub fn input(&mut self) {
+    /// Move the pointer by `stride` until it lands on a zero cell. Single steps search the tape
+    /// as a slice instead of going one cell at a time, see `Cell::find_zero`. Bigger strides take one of `fuel` for
+    /// every move, and stop with `Trap::OutOfFuel` once there's none left, with the pointer
+    /// wherever it got to.
+    pub fn scan(&mut self, stride: i32, fuel: &mut u64) -> Result<(), Trap> {
+        let here = self.cell(0)?;
+        let wraps = self.policy == TapePolicy::Wrap;
+        let found = match stride {
+            1 => C::find_zero(&self.tape[here..])
+                .map(|at| here + at)
+                .or_else(|| C::find_zero(&self.tape[..here]).filter(|_| wraps)),
+            -1 => C::rfind_zero(&self.tape[..=here]).or_else(|| {
+                C::rfind_zero(&self.tape[here..])
+                    .map(|at| here + at)
+                    .filter(|_| wraps)
+            }),
+            _ => None,
+        };
+        if let Some(at) = found {
+            self.ptr = at as isize;
+            return Ok(());
+        }
+        // Either a bigger stride, there isn't a zero anywhere, or there isn't one before the end
+        // of the tape and the policy gets to decide
+        if stride.abs() == 1 {
+            if wraps {
+                return Err(Trap::Endless);
+            }
+            self.ptr = if stride == 1 {
+                self.tape.len() as isize
+            } else {
+                -1
+            };
+        }
+        // Wrapping, a whole tape's worth of moves brings the pointer back to where it started
+        let mut moves = 0;
+        while self.get_byte()? != 0 {
+            if wraps && moves == self.tape.len() {
+                return Err(Trap::Endless);
+            }
+            if stride.abs() != 1 {
+                *fuel = fuel.checked_sub(1).ok_or(Trap::OutOfFuel)?;
+            }
+            self.shift(stride as isize);
+            moves += 1;
+        }
+        Ok(())
+    }
+
+    pub fn input(&mut self, offset: i32) -> Result<(), Trap> {
+        // Whatever the program asked for input with should be out before it's waiting on it
+        self.writer.flush().map_err(Trap::Output)?;
+        let read = match self.io {
+            IoMode::Bytes => self.read_byte()?.map(u32::from),
+            IoMode::Utf8 => self.read_code_point()?,
+        };
+        let value = match read {
+            Some(value) => value,
+            None => match self.eof {
+                EofPolicy::Zero => 0,
+                EofPolicy::MinusOne => u32::MAX,
+                // Don't even look the cell up, same as the compiled program
+                EofPolicy::Unchanged => return Ok(()),
+            },
+        };
+        let at = self.cell(offset)?;
+        self.tape[at] = C::from_u32(value);
+        Ok(())
+    }
+
+    /// The next byte of input, or `None` once it runs out.
+    fn read_byte(&mut self) -> Result<Option<u8>, Trap> {
         let mut input: [u8; 1] = [0; 1];
-        stdin()
-            .read_exact(&mut input)
-            .expect("Could not read stdin");
-        io::stdout().flush().unwrap();
-        self.tape[self.ptr] = input[0].into();
+        match self.reader.read_exact(&mut input) {
+            Ok(()) => Ok(Some(input[0])),
+            Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+            Err(error) => Err(Trap::Input(error)),
+        }
     }
 
-    pub fn output(&self) {
-        print!("{}", char::from_u32(self.tape[self.ptr]).unwrap());
-        io::stdout().flush().unwrap();
+    /// Decode the next code point from the input, exactly like `$bfc_get_utf8` in compiled
+    /// programs does: a bad first byte, or a sequence that's cut short, comes out as U+FFFD
+    /// (having eaten whatever byte showed it was bad).
+    fn read_code_point(&mut self) -> Result<Option<u32>, Trap> {
+        let Some(lead) = self.read_byte()? else {
+            return Ok(None);
+        };
+        let replacement = Ok(Some(char::REPLACEMENT_CHARACTER.into()));
+        let (mut code, more) = match lead {
+            0x00..=0x7f => return Ok(Some(lead.into())),
+            0xc0..=0xdf => (u32::from(lead & 0x1f), 1),
+            0xe0..=0xef => (u32::from(lead & 0x0f), 2),
+            0xf0..=0xf7 => (u32::from(lead & 0x07), 3),
+            _ => return replacement,
+        };
+        for _ in 0..more {
+            match self.read_byte()? {
+                Some(next) if next & 0xc0 == 0x80 => code = code << 6 | u32::from(next & 0x3f),
+                _ => return replacement,
+            }
+        }
+        Ok(Some(code))
+    }
+
+    pub fn output(&mut self, offset: i32) -> Result<(), Trap> {
+        let at = self.cell(offset)?;
+        let value = self.tape[at].to_u32();
+        let mut utf8 = [0; 4];
+        let bytes: &[u8] = match self.io {
+            // Same as `putchar`, which only ever writes the lowest byte
+            IoMode::Bytes => &[value as u8],
+            IoMode::Utf8 => char::from_u32(value)
+                .unwrap_or(char::REPLACEMENT_CHARACTER)
+                .encode_utf8(&mut utf8)
+                .as_bytes(),
+        };
+        let written = self.written + bytes.len() as u64;
+        if self.limits.output.is_some_and(|limit| written > limit) {
+            return Err(Trap::OutputLimit);
+        }
+        self.written = written;
+        self.writer
+            .write_all(bytes)
+            .and_then(|()| self.writer.flush())
+            .map_err(Trap::Output)
     }
 
-    pub fn get_byte(&self) -> u32 {
-        self.tape[self.ptr]
+    pub fn get_byte(&mut self) -> Result<u32, Trap> {
+        let at = self.cell(0)?;
+        Ok(self.tape[at].to_u32())
     }
 }
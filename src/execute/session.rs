@@ -0,0 +1,330 @@
+//! Running a program a bit at a time, for driving it from an event loop. Nothing ever blocks: a
+//! `Session` hands control back whenever the program wants input it hasn't been given yet, or
+//! after however many steps it was allowed, and carries on from exactly there when resumed.
+
+use crate::error::BfcError;
+use crate::execute::bytecode::{Bytecode, Instruction};
+use crate::execute::interpret::{step, stopped, ExecOutcome};
+use crate::execute::machine::{Cell, IoMode, Machine, Trap};
+use std::collections::VecDeque;
+use std::io::{self, Read};
+
+/// Input given to a session that the program hasn't read yet. Reading it when it's empty is
+/// `WouldBlock` until it's been closed, and the end of the input after that.
+#[derive(Debug, Default)]
+pub struct InputQueue {
+    pending: VecDeque<u8>,
+    closed: bool,
+}
+
+impl InputQueue {
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.pending.extend(bytes);
+    }
+
+    pub fn close(&mut self) {
+        self.closed = true;
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    /// Whether reading a whole unit of input in `io` mode can't block, so a `,` never gets
+    /// halfway through a code point and has to throw away what it already read.
+    fn ready(&self, io: IoMode) -> bool {
+        let Some(&lead) = self.pending.front() else {
+            return self.closed;
+        };
+        let more = match (io, lead) {
+            (IoMode::Utf8, 0xc0..=0xdf) => 1,
+            (IoMode::Utf8, 0xe0..=0xef) => 2,
+            (IoMode::Utf8, 0xf0..=0xf7) => 3,
+            _ => 0,
+        };
+        let mut continuing = self.pending.iter().skip(1).take(more);
+        // A byte that isn't a continuation ends the sequence early, as U+FFFD
+        self.closed || self.pending.len() > more || continuing.any(|next| next & 0xc0 != 0x80)
+    }
+}
+
+impl Read for InputQueue {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pending.is_empty() && !self.closed {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        self.pending.read(buf)
+    }
+}
+
+/// Why `Session::resume` handed control back.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status {
+    /// The next instruction is a `,` and there isn't enough input for it. `feed` it some, or
+    /// `close_input`, and resume.
+    NeedsInput,
+    /// Ran all the steps it was given, and there's more to go.
+    Yielded,
+    /// Done, for good. Resuming again just says so again.
+    Ended(ExecOutcome),
+}
+
+/// A program part way through running: its bytecode, where it's at in it, and the machine with
+/// the tape. Output piles up until it's taken with `take_output`.
+pub struct Session<C: Cell> {
+    bytecode: Bytecode,
+    machine: Machine<C, InputQueue, Vec<u8>>,
+    pc: usize,
+    /// Steps run over every resume, which is what `Limits::steps` counts against
+    steps: u64,
+    ended: Option<ExecOutcome>,
+}
+
+impl<C: Cell> Session<C> {
+    /// Start `bytecode` from the top on `machine`, which shouldn't have been run on yet.
+    pub fn new(bytecode: Bytecode, machine: Machine<C, InputQueue, Vec<u8>>) -> Self {
+        Session {
+            bytecode,
+            machine,
+            pc: 0,
+            steps: 0,
+            ended: None,
+        }
+    }
+
+    /// Give the program more input.
+    pub fn feed(&mut self, bytes: &[u8]) {
+        self.machine.get_reader_mut().push(bytes);
+    }
+
+    /// There won't be any more input, so once what's been fed runs out `,` gets the end of it.
+    pub fn close_input(&mut self) {
+        self.machine.get_reader_mut().close();
+    }
+
+    /// Everything output since the last time.
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(self.machine.get_writer_mut())
+    }
+
+    /// Run until the program needs input, has run `steps` more instructions, or ends. With no
+    /// `steps` it only stops for the other two. A scan with a stride past 1 pays a step for every
+    /// move, and can stop part way to yield. An error leaves the session on the instruction
+    /// that failed.
+    pub fn resume(&mut self, steps: Option<u64>) -> Result<Status, BfcError> {
+        if let Some(outcome) = self.ended {
+            return Ok(Status::Ended(outcome));
+        }
+        let code = self.bytecode.get_code();
+        let limits = self.machine.get_limits();
+        let mut budget = steps;
+        while let Some(&instruction) = code.get(self.pc) {
+            let location = self.bytecode.get_location(self.pc);
+            if let Some(limit) = limits.steps.filter(|&limit| self.steps >= limit) {
+                let outcome = ExecOutcome::FuelExhausted {
+                    steps: limit,
+                    location,
+                };
+                return Ok(self.end(outcome));
+            }
+            if budget == Some(0) {
+                return Ok(Status::Yielded);
+            }
+            if matches!(instruction, Instruction::Input(_))
+                && !self.machine.get_reader().ready(self.machine.get_io_mode())
+            {
+                return Ok(Status::NeedsInput);
+            }
+            // What a scan gets to move with, on top of the step for the instruction. Going by
+            // the budget, it always gets to move at least once, so yielding in the middle of one
+            // still gets somewhere
+            let left = limits.steps.map(|limit| limit - self.steps - 1);
+            let mut fuel = match (left, budget) {
+                (left, Some(budget)) => left.unwrap_or(u64::MAX).min((budget - 1).max(1)),
+                (Some(left), None) => left,
+                (None, None) => u64::MAX,
+            };
+            let before = fuel;
+            let result = step(
+                &self.bytecode,
+                instruction,
+                self.pc,
+                &mut self.machine,
+                &mut fuel,
+            );
+            let taken = before - fuel + 1;
+            self.steps += taken;
+            budget = budget.map(|budget| budget.saturating_sub(taken));
+            match result {
+                Ok(next) => self.pc = next,
+                // Out of budget rather than steps. Scanning from wherever the pointer got to
+                // is the same as having carried on
+                Err(Trap::OutOfFuel) if left.is_none_or(|left| before < left) => {
+                    return Ok(Status::Yielded)
+                }
+                Err(trap) => {
+                    let outcome = stopped(trap, location, &limits)?;
+                    return Ok(self.end(outcome));
+                }
+            }
+        }
+        Ok(self.end(ExecOutcome::Finished))
+    }
+
+    fn end(&mut self, outcome: ExecOutcome) -> Status {
+        self.ended = Some(outcome);
+        Status::Ended(outcome)
+    }
+
+    pub fn get_machine(&self) -> &Machine<C, InputQueue, Vec<u8>> {
+        &self.machine
+    }
+
+    pub fn get_machine_mut(&mut self) -> &mut Machine<C, InputQueue, Vec<u8>> {
+        &mut self.machine
+    }
+
+    pub fn get_bytecode(&self) -> &Bytecode {
+        &self.bytecode
+    }
+
+    /// Index of the instruction that runs next.
+    pub fn get_pc(&self) -> usize {
+        self.pc
+    }
+
+    /// line, column of the instruction that runs next, or `None` once there's nothing left.
+    pub fn get_location(&self) -> Option<(u32, u32)> {
+        (self.pc < self.bytecode.get_code().len()).then(|| self.bytecode.get_location(self.pc))
+    }
+
+    /// Instructions run so far.
+    pub fn get_steps(&self) -> u64 {
+        self.steps
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::execute::machine::{EofPolicy, Limits, TapePolicy};
+    use crate::parse::ast::generate_ast;
+    use crate::parse::optimizer::{level, optimize};
+
+    fn session(program: &str, io: IoMode) -> Session<u32> {
+        optimized(program, io, 0)
+    }
+
+    fn optimized(program: &str, io: IoMode, optimizations: u8) -> Session<u32> {
+        let ast = generate_ast(&mut program.chars()).unwrap();
+        let ast = optimize(&ast, &level(optimizations), EofPolicy::MinusOne);
+        let machine = Machine::with_io(
+            10,
+            TapePolicy::Wrap,
+            EofPolicy::MinusOne,
+            io,
+            InputQueue::default(),
+            Vec::new(),
+        );
+        Session::new(Bytecode::lower(&ast), machine)
+    }
+
+    #[test]
+    fn waits_for_input() {
+        let mut cat = session(",+[-.,+]", IoMode::Bytes);
+        assert_eq!(cat.resume(None).unwrap(), Status::NeedsInput);
+        assert_eq!(cat.resume(None).unwrap(), Status::NeedsInput);
+        cat.feed(b"hi");
+        assert_eq!(cat.resume(None).unwrap(), Status::NeedsInput);
+        assert_eq!(cat.take_output(), b"hi");
+        cat.feed(b"!");
+        cat.close_input();
+        assert_eq!(
+            cat.resume(None).unwrap(),
+            Status::Ended(ExecOutcome::Finished)
+        );
+        assert_eq!(cat.take_output(), b"!");
+        assert_eq!(cat.get_location(), None);
+    }
+
+    #[test]
+    fn whole_code_points() {
+        let mut echo = session(",.,.", IoMode::Utf8);
+        echo.feed(&"é".as_bytes()[..1]);
+        assert_eq!(echo.resume(None).unwrap(), Status::NeedsInput);
+        echo.feed(&"é".as_bytes()[1..]);
+        // A bad continuation doesn't need to wait for anything else
+        echo.feed(&[0xe2, b'x']);
+        assert_eq!(
+            echo.resume(None).unwrap(),
+            Status::Ended(ExecOutcome::Finished)
+        );
+        assert_eq!(echo.take_output(), "é\u{fffd}".as_bytes());
+    }
+
+    #[test]
+    fn yields() {
+        let mut count = session("+++++[>+<-]>.", IoMode::Bytes);
+        let mut yields = 0;
+        while count.resume(Some(3)).unwrap() == Status::Yielded {
+            assert_eq!(count.get_steps(), 3 * (yields + 1));
+            yields += 1;
+        }
+        assert_eq!(count.take_output(), [5]);
+        // Same as running it in one go, however it got split up
+        let mut whole = session("+++++[>+<-]>.", IoMode::Bytes);
+        whole.resume(None).unwrap();
+        assert_eq!(whole.get_steps(), count.get_steps());
+
+        let mut forever = session("+[]", IoMode::Bytes);
+        forever.get_machine_mut().set_limits(Limits {
+            steps: Some(10),
+            ..Limits::default()
+        });
+        assert_eq!(forever.resume(Some(4)).unwrap(), Status::Yielded);
+        assert_eq!(
+            forever.resume(None).unwrap(),
+            Status::Ended(ExecOutcome::FuelExhausted {
+                steps: 10,
+                location: (1, 2)
+            })
+        );
+    }
+
+    #[test]
+    fn endless_scans() {
+        // The tape's all nonzero by the time the last loop goes looking for a zero
+        for program in ["->+[>+]+[>]", "->+[>+]+[>>>]"] {
+            let mut stuck = optimized(program, IoMode::Bytes, 3);
+            for _ in 0..100 {
+                match stuck.resume(Some(3)).unwrap() {
+                    Status::Yielded => (),
+                    status => {
+                        assert!(matches!(status, Status::Ended(ExecOutcome::Endless { .. })));
+                        break;
+                    }
+                }
+            }
+            assert!(matches!(
+                stuck.resume(None).unwrap(),
+                Status::Ended(ExecOutcome::Endless { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn yields_in_scans() {
+        // A stride of 2 that has four moves to make, one step at a time
+        let program = "+>>+>>+>>+<<<<<<[>>]";
+        let mut stepped = optimized(program, IoMode::Bytes, 3);
+        let mut yields = 0;
+        while stepped.resume(Some(1)).unwrap() == Status::Yielded {
+            yields += 1;
+        }
+        let mut whole = optimized(program, IoMode::Bytes, 3);
+        whole.resume(None).unwrap();
+        assert!(yields > whole.get_bytecode().get_code().len());
+        assert_eq!(stepped.get_machine().get_pointer(), 8);
+        assert_eq!(whole.get_machine().get_pointer(), 8);
+    }
+}
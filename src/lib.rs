@@ -0,0 +1,200 @@
+//! bfc-rs as a library: parse a brainfuck program, optimize it, and then either interpret it or
+//! compile it to QBE IR.
+//!
+//! ```
+//! use bfc_rs::{ExecOutcome, Options, Program};
+//!
+//! let program = Program::parse(",[.,]").unwrap();
+//! let program = program.optimize(3, &Options::default());
+//! let (output, outcome) = program
+//!     .run(&Options::default(), &b"cat"[..], Vec::new())
+//!     .unwrap();
+//! assert_eq!(output, b"cat");
+//! assert_eq!(outcome, ExecOutcome::Finished);
+//! ```
+
+pub mod error;
+pub mod execute;
+pub mod parse;
+
+use std::io::{Read, Write};
+
+pub use error::BfcError;
+pub use execute::interpret::ExecOutcome;
+pub use execute::machine::{Cell, CellWidth, EofPolicy, IoMode, Limits, Machine, TapePolicy, Trap};
+pub use execute::session::{InputQueue, Session, Status};
+pub use parse::opcodes::{OpCodes, Tokens};
+pub use parse::optimizer::{OptimizerReport, OptimizerStrategies, PassStatistics};
+
+use execute::bytecode::Bytecode;
+use execute::compile::compile;
+use execute::{interpret, jit};
+use parse::ast::generate_ast;
+use parse::optimizer::{self, optimize_with_statistics};
+
+/// How the program gets run or compiled. Everything but the tape size has a matching command
+/// line flag, and the defaults are the same as the command line's.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Options {
+    pub cell_width: CellWidth,
+    /// How many cells the tape starts out with
+    pub tape_size: usize,
+    pub tape: TapePolicy,
+    pub eof: EofPolicy,
+    pub io_mode: IoMode,
+    /// Run the program as native code where there's a JIT for it, see `execute::jit`. Compiling
+    /// doesn't care.
+    pub jit: bool,
+    /// How far running gets to go, none of which compiling cares about either
+    pub limits: Limits,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            cell_width: CellWidth::ThirtyTwo,
+            tape_size: 30_000,
+            tape: TapePolicy::Wrap,
+            eof: EofPolicy::Zero,
+            io_mode: IoMode::Bytes,
+            jit: false,
+            limits: Limits::default(),
+        }
+    }
+}
+
+impl Options {
+    fn check(&self) -> Result<(), BfcError> {
+        if self.tape_size == 0 {
+            return Err(BfcError::Options(
+                "The tape needs at least one cell".to_owned(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// A brainfuck program, parsed and ready to run.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Program {
+    ast: Vec<Tokens>,
+}
+
+impl Program {
+    /// Parse `source`, failing on unmatched brackets or loops nested more than
+    /// `ast::MAX_NESTING` deep.
+    pub fn parse(source: &str) -> Result<Self, BfcError> {
+        Ok(Program {
+            ast: generate_ast(&mut source.chars())?,
+        })
+    }
+
+    pub fn from_ast(ast: Vec<Tokens>) -> Self {
+        Program { ast }
+    }
+
+    pub fn get_ast(&self) -> &[Tokens] {
+        &self.ast
+    }
+
+    pub fn into_ast(self) -> Vec<Tokens> {
+        self.ast
+    }
+
+    /// Run the passes an `-O<level>` would.
+    pub fn optimize(self, level: u8, options: &Options) -> Self {
+        self.optimize_with_passes(&optimizer::level(level), options)
+            .0
+    }
+
+    /// Run exactly `passes`, and say what each of them did.
+    pub fn optimize_with_passes(
+        self,
+        passes: &[OptimizerStrategies],
+        options: &Options,
+    ) -> (Self, OptimizerReport) {
+        let (ast, report) = optimize_with_statistics(&self.ast, passes, options.eof);
+        (Program { ast }, report)
+    }
+
+    /// Interpret the program, reading `,` from `reader` and writing `.` to `writer`, which gets
+    /// handed back once the program is done or one of `options.limits` stopped it.
+    pub fn run<R: Read, W: Write>(
+        &self,
+        options: &Options,
+        reader: R,
+        writer: W,
+    ) -> Result<(W, ExecOutcome), BfcError> {
+        options.check()?;
+        let (size, tape, eof, io) = (
+            options.tape_size,
+            options.tape,
+            options.eof,
+            options.io_mode,
+        );
+        match options.cell_width {
+            CellWidth::Eight => self.run_on(
+                Machine::<u8, R, W>::with_io(size, tape, eof, io, reader, writer),
+                options,
+            ),
+            CellWidth::Sixteen => self.run_on(
+                Machine::<u16, R, W>::with_io(size, tape, eof, io, reader, writer),
+                options,
+            ),
+            CellWidth::ThirtyTwo => self.run_on(
+                Machine::<u32, R, W>::with_io(size, tape, eof, io, reader, writer),
+                options,
+            ),
+        }
+    }
+
+    fn run_on<C: Cell, R: Read, W: Write>(
+        &self,
+        mut machine: Machine<C, R, W>,
+        options: &Options,
+    ) -> Result<(W, ExecOutcome), BfcError> {
+        machine.set_limits(options.limits);
+        let bytecode = Bytecode::lower(&self.ast);
+        let outcome = if options.jit {
+            jit::run(&bytecode, &mut machine)?
+        } else {
+            interpret::run(&bytecode, &mut machine)?
+        };
+        Ok((machine.into_writer(), outcome))
+    }
+
+    /// Start running the program a bit at a time, see `Session`, which never waits on input
+    /// itself. `C` has to be the cell for `options.cell_width`, and `options.jit` doesn't apply.
+    pub fn start<C: Cell>(&self, options: &Options) -> Result<Session<C>, BfcError> {
+        options.check()?;
+        if C::WIDTH != options.cell_width {
+            return Err(BfcError::Options(format!(
+                "The session's cells have {} bits, but the options ask for {}",
+                C::WIDTH.bytes() * 8,
+                options.cell_width.bytes() * 8
+            )));
+        }
+        let mut machine = Machine::with_io(
+            options.tape_size,
+            options.tape,
+            options.eof,
+            options.io_mode,
+            InputQueue::default(),
+            Vec::new(),
+        );
+        machine.set_limits(options.limits);
+        Ok(Session::new(Bytecode::lower(&self.ast), machine))
+    }
+
+    /// Compile the program to QBE IR. Also says whether it can be linked statically.
+    /// `file_location` is the source file debug info points at when `debug` is on.
+    pub fn compile(
+        &self,
+        options: &Options,
+        debug: bool,
+        file_location: &str,
+    ) -> Result<(String, bool), BfcError> {
+        options.check()?;
+        Ok(compile(&self.ast, options, debug, file_location))
+    }
+}
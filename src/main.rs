@@ -1,35 +1,36 @@
 mod args;
-mod execute;
-mod parse;
+mod build;
+mod debug;
 
-use std::ffi::OsStr;
 use std::fs;
-use std::fs::File;
-use std::io::Write;
-use std::path::Path;
-use std::process::Command;
+use std::io::{stdin, stdout};
 
 use args::args::Args;
 use args::args::Commands;
-use clap::Parser;
-use execute::compile::compile;
-use execute::interpret::interpret;
-use execute::machine::Machine;
-use parse::ast::{balance_brackets, generate_ast};
-use parse::optimizer::{optimize, OptimizerStrategies};
+use args::args::OptimizeArgs;
+use args::args::PassArg;
+use bfc_rs::execute::jit;
+use bfc_rs::parse::optimizer::DEFAULT_LEVEL;
+use bfc_rs::{
+    BfcError, CellWidth, ExecOutcome, Limits, OptimizerStrategies, Options, Program, TapePolicy,
+};
+use build::Build;
+use clap::{Parser, ValueEnum};
+use debug::Debugger;
 use reedline::{DefaultPrompt, Reedline, Signal};
 
 fn main() {
     let args = Args::parse();
-    let optimizings = vec![
-        OptimizerStrategies::Contractions,
-        OptimizerStrategies::ClearLoop,
-        OptimizerStrategies::DeadCode,
-        OptimizerStrategies::PureCode,
-    ];
+    if let Err(error) = run(&args) {
+        eprintln!("{error}");
+        std::process::exit(error.exit_code());
+    }
+}
 
+fn run(args: &Args) -> Result<(), BfcError> {
     match &args.cmd {
         Commands::Shell {} => {
+            let options = Options::default();
             let mut line_editor = Reedline::create();
             let prompt = DefaultPrompt::new(
                 reedline::DefaultPromptSegment::Empty,
@@ -52,10 +53,16 @@ fn main() {
                             println!("\n# Example program:");
                             println!("    ++++++++[>++++[>++>+++>+++>+<<<<-]\n    >+>+>->>+[<]<-]>>.>---.+++++++..++\n    +.>>.<-.<.+++.------.--------.>>+.>++.");
                         }
-                        let ast =
-                            optimize(&generate_ast(&mut buffer.chars()), &optimizings.clone());
-                        let mut machine = Machine::new(30_000);
-                        interpret(&ast, &mut machine);
+                        let program = match Program::parse(&buffer) {
+                            Ok(program) => program.optimize(DEFAULT_LEVEL, &options),
+                            Err(nar) => {
+                                eprintln!("{nar}");
+                                continue;
+                            }
+                        };
+                        if let Err(nar) = program.run(&options, stdin(), stdout()) {
+                            eprintln!("{nar}");
+                        }
                     }
                     Ok(Signal::CtrlC | Signal::CtrlD) => {
                         println!("\nBye bye");
@@ -69,84 +76,173 @@ fn main() {
         }
         Commands::Compile {
             emit_ir,
+            emit,
+            output,
+            keep_temps,
             debug,
             dynamic,
+            bounds_check,
+            cell_width,
+            tape,
+            eof,
+            io_mode,
+            optimize: optimize_args,
             rest,
         } => {
-            let file_contents = fs::read_to_string(rest).expect("Could not read file");
-            let mut file_contents = file_contents.chars();
-            if let Err(nar) = balance_brackets(&file_contents) {
-                eprintln!("{nar}");
-                std::process::exit(1);
-            }
+            let file_contents =
+                fs::read_to_string(rest).map_err(BfcError::io("Could not read file"))?;
             if !*emit_ir {
                 println!(">> Generating AST...");
             }
-            let mut ast = generate_ast(&mut file_contents);
-            if !debug {
+            let mut program = Program::parse(&file_contents)?;
+            let options = Options {
+                cell_width: (*cell_width).into(),
+                tape: if *bounds_check {
+                    TapePolicy::Abort
+                } else {
+                    (*tape).into()
+                },
+                eof: (*eof).into(),
+                io_mode: (*io_mode).into(),
+                ..Options::default()
+            };
+            let optimizings = optimize_args.strategies(if *debug { 0 } else { DEFAULT_LEVEL });
+            if !optimizings.is_empty() {
                 if !*emit_ir {
                     println!(">> Optimizing AST...");
                 }
-                ast = optimize(&ast, &optimizings);
+                program = run_optimizer(program, &optimizings, optimize_args, &options);
             }
-            let file_name = rest;
-            let machine = Machine::new(30_000);
             if !*emit_ir {
                 println!(">> Compiling to IR...");
             }
-            let (text, static_comp) = compile(&ast, &machine, *debug, rest.to_str().unwrap());
+            let file_location = rest.to_string_lossy();
+            let (text, static_comp) = program.compile(&options, *debug, &file_location)?;
             if *emit_ir {
                 print!("{text}");
-                std::process::exit(0);
+                return Ok(());
             }
-            let tmp_path = format!(
-                "/tmp/bfc-rs-{}",
-                file_name.file_name().unwrap().to_str().unwrap()
-            );
-            let mut tmp = File::create(&tmp_path).unwrap();
-            write!(tmp, "{text}").unwrap();
-            let s_path = format!(
-                "/tmp/bfc-rs-{}.s",
-                file_name.file_name().unwrap().to_str().unwrap()
+            Build {
+                emit,
+                output: output.as_deref(),
+                keep_temps: *keep_temps,
+                static_link: !*dynamic && static_comp,
+                debug: *debug,
+            }
+            .run(&text, rest)?;
+        }
+        Commands::Interpret {
+            cell_width,
+            tape,
+            eof,
+            io_mode,
+            jit,
+            max_steps,
+            max_tape,
+            max_output,
+            optimize: optimize_args,
+            rest,
+        } => {
+            if *jit && !jit::AVAILABLE {
+                eprintln!(">> There's no JIT for this platform, interpreting instead");
+            }
+            let file_contents =
+                fs::read_to_string(rest).map_err(BfcError::io("Could not read file"))?;
+            let program = Program::parse(&file_contents)?;
+            let options = Options {
+                cell_width: (*cell_width).into(),
+                tape: (*tape).into(),
+                eof: (*eof).into(),
+                io_mode: (*io_mode).into(),
+                jit: *jit,
+                limits: Limits {
+                    steps: *max_steps,
+                    tape: *max_tape,
+                    output: *max_output,
+                },
+                ..Options::default()
+            };
+            let program = run_optimizer(
+                program,
+                &optimize_args.strategies(DEFAULT_LEVEL),
+                optimize_args,
+                &options,
             );
-            println!(">> Generating assembly...");
-            Command::new("qbe")
-                .args(["-o", &s_path, &tmp_path])
-                .output()
-                .expect("Could not run qbe");
-            println!(">> Compiling assembly to final binary...");
-            Command::new("cc")
-                .args([
-                    if *dynamic || !static_comp {
-                        "-dynamic"
-                    } else {
-                        "-static"
-                    },
-                    "-flto",
-                    if *debug { "-g" } else { "-s" },
-                    s_path.as_str(),
-                    "-o",
-                    Path::new(&file_name)
-                        .file_stem()
-                        .and_then(OsStr::to_str)
-                        .unwrap_or("Unknown"),
-                ])
-                .output()
-                .expect("Could not run cc");
-            fs::remove_file(tmp_path).unwrap();
-            fs::remove_file(s_path).unwrap();
+            let (_, outcome) = program.run(&options, stdin(), stdout())?;
+            if outcome != ExecOutcome::Finished {
+                return Err(BfcError::Exhausted(outcome));
+            }
         }
-        Commands::Interpret { rest } => {
-            let file_contents = fs::read_to_string(rest).expect("Could not read file");
-            let mut file_contents = file_contents.chars();
-            if let Err(nar) = balance_brackets(&file_contents) {
-                eprintln!("{nar}");
-                std::process::exit(1);
+        Commands::Debug {
+            cell_width,
+            tape,
+            eof,
+            io_mode,
+            optimize: optimize_args,
+            rest,
+        } => {
+            let file_contents =
+                fs::read_to_string(rest).map_err(BfcError::io("Could not read file"))?;
+            let program = Program::parse(&file_contents)?;
+            let options = Options {
+                cell_width: (*cell_width).into(),
+                tape: (*tape).into(),
+                eof: (*eof).into(),
+                io_mode: (*io_mode).into(),
+                ..Options::default()
+            };
+            // Unoptimized, every step is a character of the source
+            let program = run_optimizer(
+                program,
+                &optimize_args.strategies(0),
+                optimize_args,
+                &options,
+            );
+            match options.cell_width {
+                CellWidth::Eight => {
+                    Debugger::new(program.start::<u8>(&options)?, &file_contents).run()?
+                }
+                CellWidth::Sixteen => {
+                    Debugger::new(program.start::<u16>(&options)?, &file_contents).run()?
+                }
+                CellWidth::ThirtyTwo => {
+                    Debugger::new(program.start::<u32>(&options)?, &file_contents).run()?
+                }
             }
-            let ast = generate_ast(&mut file_contents);
-            let ast = optimize(&ast, &optimizings);
-            let mut machine = Machine::new(30_000);
-            interpret(&ast, &mut machine);
         }
     }
+    Ok(())
+}
+
+/// Optimize the program, and tell the user what every pass did if they asked for it.
+fn run_optimizer(
+    program: Program,
+    optimizings: &[OptimizerStrategies],
+    optimize_args: &OptimizeArgs,
+    options: &Options,
+) -> Program {
+    let (program, report) = program.optimize_with_passes(optimizings, options);
+    if optimize_args.pass_stats {
+        if report.settled {
+            eprintln!(">> Optimizer settled after {} round(s)", report.iterations);
+        } else {
+            eprintln!(">> Optimizer gave up after {} rounds", report.iterations);
+        }
+        eprintln!(
+            "{:<16}{:>10}{:>10}{:>14}",
+            "pass", "removed", "rewrites", "time"
+        );
+        for pass in &report.passes {
+            eprintln!(
+                "{:<16}{:>10}{:>10}{:>14}",
+                PassArg::from(pass.strategy)
+                    .to_possible_value()
+                    .map_or(String::new(), |name| name.get_name().to_owned()),
+                pass.nodes_removed,
+                pass.rewrites,
+                format!("{:.3?}", pass.time)
+            );
+        }
+    }
+    program
 }
@@ -1,3 +1,4 @@
 pub mod ast;
+pub mod fold;
 pub mod opcodes;
 pub mod optimizer;
@@ -1,58 +1,232 @@
+use crate::error::BfcError;
 use crate::parse::opcodes::OpCodes;
 use crate::parse::opcodes::Tokens;
-use anyhow::anyhow;
-
-pub fn balance_brackets(program: &std::str::Chars) -> std::result::Result<(), anyhow::Error> {
-    let left = program.clone().filter(|&n| n == ']').count();
-    let right = program.clone().filter(|&n| n == '[').count();
-    if left != right {
-        Err(anyhow!(format!(
-            "Could not balance brackets:\n'[': {}\n']': {}",
-            right, left
-        )))
-    } else {
-        Ok(())
+
+/// How deep loops can be nested. Parsing, optimizing and compiling all recurse into loop bodies,
+/// and this keeps them inside the 8 MiB stack the main thread gets, with room to spare in a
+/// release build.
+pub const MAX_NESTING: usize = 1000;
+
+/// Match every `[` with a `]`, reporting each one that is left over along with where it is, or
+/// else the first `[` nested deeper than `MAX_NESTING`.
+pub fn balance_brackets(program: &std::str::Chars) -> Result<(), BfcError> {
+    let source = program.as_str();
+    let mut open: Vec<(u32, u32)> = vec![];
+    let mut unmatched: Vec<(char, (u32, u32))> = vec![];
+    let mut too_deep = None;
+    let mut line_num = 1;
+    let mut column_num = 0;
+    for part in source.chars() {
+        column_num += 1;
+        match part {
+            '[' => {
+                open.push((line_num, column_num));
+                if open.len() > MAX_NESTING {
+                    too_deep = too_deep.or(Some((line_num, column_num)));
+                }
+            }
+            ']' => match open.pop() {
+                Some(_) => (),
+                None => unmatched.push((']', (line_num, column_num))),
+            },
+            '\n' => {
+                line_num += 1;
+                column_num = 0;
+            }
+            _ => (),
+        }
+    }
+    unmatched.extend(open.into_iter().map(|location| ('[', location)));
+    unmatched.sort_by_key(|(_, location)| *location);
+
+    if unmatched.is_empty() {
+        let Some(location) = too_deep else {
+            return Ok(());
+        };
+        let report = format!(
+            "Loops nested more than {MAX_NESTING} deep at {}:{}\n{}",
+            location.0,
+            location.1,
+            snippet(source, location)
+        );
+        return Err(BfcError::Parse { unmatched, report });
+    }
+    let report = unmatched
+        .iter()
+        .map(|(bracket, location)| {
+            format!(
+                "Unmatched '{}' at {}:{}\n{}",
+                bracket,
+                location.0,
+                location.1,
+                snippet(source, *location)
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    Err(BfcError::Parse { unmatched, report })
+}
+
+/// Show the offending source line with a caret underneath `location`.
+pub fn snippet(source: &str, location: (u32, u32)) -> String {
+    let line = source.lines().nth(location.0 as usize - 1).unwrap_or("");
+    let gutter = " ".repeat(location.0.to_string().len());
+    // Keep tabs so the caret lines up with what the terminal shows
+    let padding: String = line
+        .chars()
+        .take(location.1 as usize - 1)
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    format!("{gutter} |\n{} | {line}\n{gutter} | {padding}^", location.0)
+}
+
+/// If nothing but moves and comments come between `location` and a `]`, where the `[` that `]`
+/// closes is. A move right before a `]` runs as part of jumping back, so stopping anywhere in
+/// there is stopping at the `]`.
+pub fn closing_loop(source: &str, location: (u32, u32)) -> Option<(u32, u32)> {
+    let mut open = vec![];
+    let mut line_num = 1;
+    let mut column_num = 0;
+    for part in source.chars() {
+        column_num += 1;
+        let here = (line_num, column_num);
+        match part {
+            '[' if here < location => open.push(here),
+            ']' if here < location => {
+                open.pop();
+            }
+            ']' => return open.last().copied(),
+            '+' | '-' | '.' | ',' | '[' if here >= location => return None,
+            '\n' => {
+                line_num += 1;
+                column_num = 0;
+            }
+            _ => (),
+        }
     }
+    None
 }
 
-pub fn generate_ast(program: &mut std::str::Chars) -> Vec<Tokens> {
-    generate_ast_internal(program, None, None)
+/// Where every `#` in the source is, which `debug` stops the program at.
+pub fn breakpoints(source: &str) -> Vec<(u32, u32)> {
+    let mut found = vec![];
+    let mut line_num = 1;
+    let mut column_num = 0;
+    for part in source.chars() {
+        column_num += 1;
+        match part {
+            '#' => found.push((line_num, column_num)),
+            '\n' => {
+                line_num += 1;
+                column_num = 0;
+            }
+            _ => (),
+        }
+    }
+    found
+}
+
+/// Parse the program, failing if its brackets don't match up.
+pub fn generate_ast(program: &mut std::str::Chars) -> Result<Vec<Tokens>, BfcError> {
+    balance_brackets(program)?;
+    let mut line_num = 1;
+    let mut column_num = 0;
+    Ok(generate_ast_internal(
+        program,
+        &mut line_num,
+        &mut column_num,
+    ))
 }
 
 fn generate_ast_internal(
     program: &mut std::str::Chars,
-    line_num: Option<u32>,
-    column_num: Option<u32>,
+    line_num: &mut u32,
+    column_num: &mut u32,
 ) -> Vec<Tokens> {
     let mut out = vec![];
-    let mut line_num = line_num.unwrap_or(1);
-    let mut column_num = column_num.unwrap_or(0);
     while let Some(part) = program.next() {
-        column_num += 1;
+        *column_num += 1;
+        let location = (*line_num, *column_num);
         match part {
-            '>' => out.push(Tokens::new(OpCodes::Inc(1), (line_num, column_num))),
-            '<' => out.push(Tokens::new(OpCodes::Dec(1), (line_num, column_num))),
-            '+' => out.push(Tokens::new(OpCodes::Add(1), (line_num, column_num))),
-            '-' => out.push(Tokens::new(OpCodes::Sub(1), (line_num, column_num))),
-            '.' => out.push(Tokens::new(OpCodes::Output, (line_num, column_num))),
-            ',' => out.push(Tokens::new(OpCodes::Input, (line_num, column_num))),
+            '>' => out.push(Tokens::new(OpCodes::Inc(1), location)),
+            '<' => out.push(Tokens::new(OpCodes::Dec(1), location)),
+            '+' => out.push(Tokens::new(OpCodes::Add(1, 0), location)),
+            '-' => out.push(Tokens::new(OpCodes::Sub(1, 0), location)),
+            '.' => out.push(Tokens::new(OpCodes::Output(0), location)),
+            ',' => out.push(Tokens::new(OpCodes::Input(0), location)),
             '[' => {
+                // The loop body shares our counters so that anything after the matching `]`
+                // still knows which line it's on.
                 out.push(Tokens::new(
-                    OpCodes::Loop(generate_ast_internal(
-                        program,
-                        Some(line_num),
-                        Some(column_num),
-                    )),
-                    (line_num, column_num),
+                    OpCodes::Loop(generate_ast_internal(program, line_num, column_num)),
+                    location,
                 ));
             }
             ']' => break,
             '\n' => {
-                line_num += 1;
-                column_num = 0;
+                *line_num += 1;
+                *column_num = 0;
             }
             _ => (), /* Comments probably */
         }
     }
     out
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unmatched(source: &str) -> Vec<(char, (u32, u32))> {
+        match balance_brackets(&source.chars()) {
+            Err(BfcError::Parse { unmatched, .. }) => unmatched,
+            other => panic!("{other:?}"),
+        }
+    }
+
+    #[test]
+    fn brackets() {
+        assert!(balance_brackets(&"+[>[-]<]".chars()).is_ok());
+        assert_eq!(unmatched("]["), [(']', (1, 1)), ('[', (1, 2))]);
+        // The inner pair matches, so only the outer `[` is left
+        assert_eq!(unmatched("+[[-]"), [('[', (1, 2))]);
+        assert_eq!(unmatched("[[[]]"), [('[', (1, 1))]);
+    }
+
+    #[test]
+    fn sorted_reports() {
+        // One report per bracket, in the order they come in the source
+        let source = "[\n]]\n[\n+]]\n[";
+        assert_eq!(
+            unmatched(source),
+            [(']', (2, 2)), (']', (4, 3)), ('[', (5, 1))]
+        );
+        let report = balance_brackets(&source.chars()).unwrap_err().to_string();
+        let at: Vec<_> = report
+            .lines()
+            .filter(|line| line.starts_with("Unmatched"))
+            .collect();
+        assert_eq!(
+            at,
+            [
+                "Unmatched ']' at 2:2",
+                "Unmatched ']' at 4:3",
+                "Unmatched '[' at 5:1"
+            ]
+        );
+    }
+
+    #[test]
+    fn carets() {
+        assert_eq!(snippet("+[-", (1, 2)), "  |\n1 | +[-\n  |  ^");
+        // Tabs stay tabs, so the caret is as far along as the terminal puts the bracket
+        assert_eq!(
+            snippet("+\n\t+ \t[", (2, 5)),
+            "  |\n2 | \t+ \t[\n  | \t  \t^"
+        );
+        assert_eq!(
+            snippet(&format!("{}]", "\n".repeat(9)), (10, 1)),
+            "   |\n10 | ]\n   | ^"
+        );
+    }
+}
@@ -0,0 +1,38 @@
+use crate::parse::opcodes::OpCodes;
+use crate::parse::opcodes::Tokens;
+
+/// A rewrite of the AST. Passes only say what to do with a single token or a single block (one
+/// nesting level of a `Vec<Tokens>`), and `fold` makes sure they get to see every loop body,
+/// innermost first.
+pub trait Fold {
+    /// Rewrite one token into any number of tokens. A loop's body has already been folded by the
+    /// time the loop gets here.
+    fn fold_token(&self, token: Tokens) -> Vec<Tokens> {
+        vec![token]
+    }
+
+    /// Rewrite a block once every token in it has been through `fold_token`. `top_level` is true
+    /// for the program itself and false for loop bodies.
+    fn fold_block(&self, block: Vec<Tokens>, _top_level: bool) -> Vec<Tokens> {
+        block
+    }
+}
+
+pub fn fold(folder: &dyn Fold, ast: &[Tokens]) -> Vec<Tokens> {
+    fold_nested(folder, ast, true)
+}
+
+fn fold_nested(folder: &dyn Fold, ast: &[Tokens], top_level: bool) -> Vec<Tokens> {
+    let mut block: Vec<Tokens> = vec![];
+    for part in ast {
+        let part = match part.get_type() {
+            OpCodes::Loop(x) => Tokens::new(
+                OpCodes::Loop(fold_nested(folder, x, false)),
+                part.get_location().to_owned(),
+            ),
+            _ => part.to_owned(),
+        };
+        block.extend(folder.fold_token(part));
+    }
+    folder.fold_block(block, top_level)
+}
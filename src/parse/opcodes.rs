@@ -1,19 +1,27 @@
 use core::fmt;
 
-#[derive(Debug, Clone, PartialEq)]
+/// The `i32` carried by cell operations is an offset from the pointer to the cell they act on.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum OpCodes {
-    Add(u32),
-    Sub(u32),
+    Add(u32, i32),
+    Sub(u32, i32),
     Inc(u32),
     Dec(u32),
-    Output,
-    Input,
+    Output(i32),
+    Input(i32),
     Loop(Vec<Tokens>),
     // Special optimizations
-    Clear,
+    Clear(i32),
+    /// Clear the cell and then add to it, all as one store.
+    Set(u32, i32),
+    /// (offset, factor): add `factor` times the current cell to each offset cell, then clear the
+    /// current cell.
+    MulAdd(Vec<(i32, i32)>),
+    /// Move the pointer by the stride until it lands on a zero cell.
+    Scan(i32),
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Tokens {
     code: OpCodes,
     /// line, column
@@ -37,19 +45,64 @@ impl Tokens {
 impl fmt::Display for OpCodes {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            Self::Add(x) => write!(fmt, "{:+<1$}", "", *x as usize).unwrap(),
-            Self::Sub(x) => write!(fmt, "{:-<1$}", "", *x as usize).unwrap(),
-            Self::Inc(x) => write!(fmt, "{:><1$}", "", *x as usize).unwrap(),
-            Self::Dec(x) => write!(fmt, "{:<<1$}", "", *x as usize).unwrap(),
-            Self::Output => write!(fmt, ".").unwrap(),
-            Self::Input => write!(fmt, ",").unwrap(),
-            Self::Clear => write!(fmt, "[-]").unwrap(),
+            Self::Add(x, offset) => write!(
+                fmt,
+                "{}{:+<width$}{}",
+                Self::moves(*offset),
+                "",
+                Self::moves(-offset),
+                width = *x as usize
+            )?,
+            Self::Sub(x, offset) => write!(
+                fmt,
+                "{}{:-<width$}{}",
+                Self::moves(*offset),
+                "",
+                Self::moves(-offset),
+                width = *x as usize
+            )?,
+            Self::Inc(x) => write!(fmt, "{:><1$}", "", *x as usize)?,
+            Self::Dec(x) => write!(fmt, "{:<<1$}", "", *x as usize)?,
+            Self::Output(offset) => {
+                write!(fmt, "{}.{}", Self::moves(*offset), Self::moves(-offset))?
+            }
+            Self::Input(offset) => {
+                write!(fmt, "{},{}", Self::moves(*offset), Self::moves(-offset))?
+            }
+            Self::Clear(offset) => {
+                write!(fmt, "{}[-]{}", Self::moves(*offset), Self::moves(-offset))?
+            }
+            Self::Set(x, offset) => {
+                write!(fmt, "{}[-]", Self::moves(*offset))?;
+                // Small enough to count down to should be counted down to
+                if *x > i32::MAX as u32 {
+                    write!(fmt, "{}", Self::Sub(x.wrapping_neg(), 0))?;
+                } else {
+                    write!(fmt, "{}", Self::Add(*x, 0))?;
+                }
+                write!(fmt, "{}", Self::moves(-offset))?;
+            }
+            Self::MulAdd(x) => {
+                write!(fmt, "[-")?;
+                let mut position = 0;
+                for (offset, factor) in x {
+                    write!(fmt, "{}", Self::moves(*offset - position))?;
+                    if *factor < 0 {
+                        write!(fmt, "{}", Self::Sub(factor.unsigned_abs(), 0))?;
+                    } else {
+                        write!(fmt, "{}", Self::Add(factor.unsigned_abs(), 0))?;
+                    }
+                    position = *offset;
+                }
+                write!(fmt, "{}]", Self::moves(-position))?;
+            }
+            Self::Scan(stride) => write!(fmt, "[{}]", Self::moves(*stride))?,
             Self::Loop(x) => {
-                write!(fmt, "[").unwrap();
+                write!(fmt, "[")?;
                 for item in x {
-                    write!(fmt, "{}", item.get_type()).unwrap();
+                    write!(fmt, "{}", item.get_type())?;
                 }
-                write!(fmt, "]").unwrap();
+                write!(fmt, "]")?;
             }
         }
         Ok(())
@@ -59,11 +112,20 @@ impl fmt::Display for OpCodes {
 impl OpCodes {
     pub fn opposite(&self) -> Option<Self> {
         match self {
-            OpCodes::Add(x) => Some(OpCodes::Sub(*x)),
-            OpCodes::Sub(x) => Some(OpCodes::Add(*x)),
+            OpCodes::Add(x, offset) => Some(OpCodes::Sub(*x, *offset)),
+            OpCodes::Sub(x, offset) => Some(OpCodes::Add(*x, *offset)),
             OpCodes::Inc(x) => Some(OpCodes::Dec(*x)),
             OpCodes::Dec(x) => Some(OpCodes::Inc(*x)),
             _ => None,
         }
     }
+
+    /// Pointer movement by a signed amount.
+    pub fn moves(by: i32) -> Self {
+        if by < 0 {
+            OpCodes::Dec(by.unsigned_abs())
+        } else {
+            OpCodes::Inc(by.unsigned_abs())
+        }
+    }
 }
@@ -1,179 +1,863 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::execute::machine::EofPolicy;
+use crate::parse::fold::{fold, Fold};
 use crate::parse::opcodes::OpCodes;
 
 use super::opcodes::Tokens;
 
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum OptimizerStrategies {
+    /// Merge runs of the same instruction
     Contractions,
+    /// Turn `[-]` into a single clear
     ClearLoop,
+    /// Remove code that can never do anything
     DeadCode,
+    /// Remove code at the end of the program with no side effects
     PureCode,
+    /// Turn copy and multiplication loops into straight-line code
+    MultiplyLoop,
+    /// Address cells relative to the pointer instead of moving it around
+    Offsets,
+    /// Turn `[>]` and `[<]` into a search for the next zero cell
+    ScanLoop,
+    /// Drop writes that `,` overwrites (does nothing with `--eof unchanged`, where `,` can leave
+    /// them be)
+    IoClobber,
+    /// Fuse a clear and the additions after it into a single store
+    SetValue,
 }
 
-pub fn optimize(ast: &[Tokens], optimizers: &[OptimizerStrategies]) -> Vec<Tokens> {
-    let mut new_ast: Vec<Tokens> = ast.to_owned();
-    if optimizers.contains(&OptimizerStrategies::ClearLoop) {
-        new_ast = clear(&new_ast);
+/// The highest level `level` accepts.
+pub const MAX_LEVEL: u8 = 3;
+
+/// The level to run without an `-O`. These are the passes that always used to run, the others
+/// have to be asked for.
+pub const DEFAULT_LEVEL: u8 = 1;
+
+/// Which passes an `-O<level>` runs.
+pub fn level(level: u8) -> Vec<OptimizerStrategies> {
+    let mut strategies = vec![];
+    if level >= 1 {
+        strategies.extend([
+            OptimizerStrategies::Contractions,
+            OptimizerStrategies::ClearLoop,
+            OptimizerStrategies::DeadCode,
+            OptimizerStrategies::PureCode,
+        ]);
     }
-    if optimizers.contains(&OptimizerStrategies::DeadCode) {
-        new_ast = clear_dead_code(&new_ast);
+    if level >= 2 {
+        strategies.extend([
+            OptimizerStrategies::MultiplyLoop,
+            OptimizerStrategies::ScanLoop,
+            OptimizerStrategies::SetValue,
+        ]);
     }
-    if optimizers.contains(&OptimizerStrategies::Contractions) {
-        new_ast = contract(&new_ast);
+    if level >= 3 {
+        strategies.extend([OptimizerStrategies::IoClobber, OptimizerStrategies::Offsets]);
     }
-    if optimizers.contains(&OptimizerStrategies::PureCode) {
-        new_ast = remove_pure(&new_ast);
+    strategies
+}
+
+/// Every pass, in the order they get run in each round.
+fn passes(eof: EofPolicy) -> [(OptimizerStrategies, Box<dyn Fold>); 9] {
+    [
+        (OptimizerStrategies::ClearLoop, Box::new(Clear)),
+        (OptimizerStrategies::DeadCode, Box::new(DeadCode)),
+        (OptimizerStrategies::Contractions, Box::new(Contract)),
+        (OptimizerStrategies::MultiplyLoop, Box::new(Multiply)),
+        (OptimizerStrategies::ScanLoop, Box::new(Scan)),
+        (OptimizerStrategies::SetValue, Box::new(Set)),
+        (OptimizerStrategies::IoClobber, Box::new(IoClobber { eof })),
+        (OptimizerStrategies::Offsets, Box::new(Offsets)),
+        (OptimizerStrategies::PureCode, Box::new(RemovePure)),
+    ]
+}
+
+/// How many rounds of passes to run before giving up on the AST settling down.
+pub const MAX_ITERATIONS: usize = 16;
+
+/// What a single pass did over every round of `optimize_with_statistics`.
+#[derive(Clone, Debug)]
+pub struct PassStatistics {
+    pub strategy: OptimizerStrategies,
+    /// Nodes (loop bodies included) that went away
+    pub nodes_removed: usize,
+    /// Nodes that were replaced by something new
+    pub rewrites: usize,
+    pub time: Duration,
+}
+
+#[derive(Clone, Debug)]
+pub struct OptimizerReport {
+    /// Rounds run, including the last one where nothing changed
+    pub iterations: usize,
+    /// Whether a round went by without anything changing, rather than running out of rounds
+    pub settled: bool,
+    pub passes: Vec<PassStatistics>,
+}
+
+pub fn optimize(ast: &[Tokens], optimizers: &[OptimizerStrategies], eof: EofPolicy) -> Vec<Tokens> {
+    optimize_with_statistics(ast, optimizers, eof).0
+}
+
+/// Keep running the passes in `optimizers` until the AST stops changing (or `MAX_ITERATIONS` is
+/// hit), since one pass can open up more work for another. `eof` is what `,` does once the input
+/// runs out, which decides whether it always overwrites its cell.
+pub fn optimize_with_statistics(
+    ast: &[Tokens],
+    optimizers: &[OptimizerStrategies],
+    eof: EofPolicy,
+) -> (Vec<Tokens>, OptimizerReport) {
+    let mut new_ast: Vec<Tokens> = ast.to_owned();
+    let passes: Vec<_> = passes(eof)
+        .into_iter()
+        .filter(|(strategy, _)| optimizers.contains(strategy))
+        .collect();
+    let mut report = OptimizerReport {
+        iterations: 0,
+        settled: false,
+        passes: passes
+            .iter()
+            .map(|(strategy, _)| PassStatistics {
+                strategy: *strategy,
+                nodes_removed: 0,
+                rewrites: 0,
+                time: Duration::ZERO,
+            })
+            .collect(),
+    };
+    while report.iterations < MAX_ITERATIONS {
+        report.iterations += 1;
+        let mut changed = false;
+        for ((_, pass), statistics) in passes.iter().zip(report.passes.iter_mut()) {
+            let start = Instant::now();
+            let optimized = fold(pass.as_ref(), &new_ast);
+            statistics.time += start.elapsed();
+            if optimized != new_ast {
+                statistics.nodes_removed +=
+                    count_nodes(&new_ast).saturating_sub(count_nodes(&optimized));
+                statistics.rewrites += count_rewrites(&new_ast, &optimized);
+                new_ast = optimized;
+                changed = true;
+            }
+        }
+        if !changed {
+            report.settled = true;
+            break;
+        }
     }
-    new_ast
+    (new_ast, report)
 }
 
-fn contract(ast: &[Tokens]) -> Vec<Tokens> {
-    let mut new_ast: Vec<Tokens> = vec![];
-    let mut p = ast.iter().peekable();
-    while let Some(op) = p.next() {
-        match op.get_type() {
-            OpCodes::Add(x) => {
-                let mut counter = *x as usize;
-                while Some(op) == p.peek().copied() {
-                    counter += 1;
-                    p.next();
+fn count_nodes(ast: &[Tokens]) -> usize {
+    ast.iter()
+        .map(|part| match part.get_type() {
+            OpCodes::Loop(x) => 1 + count_nodes(x),
+            _ => 1,
+        })
+        .sum()
+}
+
+/// How many nodes in `after` weren't already somewhere in `before`. Loops only count by where
+/// they are, since their bodies get looked at node by node anyway.
+fn count_rewrites(before: &[Tokens], after: &[Tokens]) -> usize {
+    fn shallow(ast: &[Tokens], nodes: &mut Vec<Tokens>) {
+        for part in ast {
+            match part.get_type() {
+                OpCodes::Loop(x) => {
+                    nodes.push(Tokens::new(OpCodes::Loop(vec![]), part.get_location()));
+                    shallow(x, nodes);
                 }
-                new_ast.push(Tokens::new(
-                    OpCodes::Add(u32::try_from(counter).unwrap()),
-                    op.get_location().to_owned(),
-                ));
+                _ => nodes.push(part.to_owned()),
             }
-            OpCodes::Sub(x) => {
-                let mut counter = *x as usize;
-                while Some(op) == p.peek().copied() {
-                    counter += 1;
-                    p.next();
-                }
-                new_ast.push(Tokens::new(
-                    OpCodes::Sub(u32::try_from(counter).unwrap()),
-                    op.get_location().to_owned(),
-                ));
+        }
+    }
+    let mut seen: HashMap<Tokens, usize> = HashMap::new();
+    let mut nodes = vec![];
+    shallow(before, &mut nodes);
+    for node in nodes.drain(..) {
+        *seen.entry(node).or_default() += 1;
+    }
+    shallow(after, &mut nodes);
+    nodes
+        .into_iter()
+        .filter(|node| match seen.get_mut(node) {
+            Some(count) if *count > 0 => {
+                *count -= 1;
+                false
             }
-            OpCodes::Inc(x) => {
-                let mut counter = *x as usize;
-                while Some(op) == p.peek().copied() {
-                    counter += 1;
+            _ => true,
+        })
+        .count()
+}
+
+/// Fold every run of `+`/`-` on the same cell into its net change, and every run of `>`/`<` into
+/// its net movement, dropping runs that cancel out completely.
+struct Contract;
+
+impl Fold for Contract {
+    fn fold_block(&self, block: Vec<Tokens>, _top_level: bool) -> Vec<Tokens> {
+        let mut new_ast: Vec<Tokens> = vec![];
+        let mut p = block.into_iter().peekable();
+        while let Some(op) = p.next() {
+            if let Some((mut delta, offset)) = cell_delta(op.get_type()) {
+                while let Some((step, _)) = p
+                    .peek()
+                    .and_then(|next| cell_delta(next.get_type()))
+                    .filter(|(_, at)| *at == offset)
+                {
+                    delta = delta.wrapping_add(step);
                     p.next();
                 }
-                new_ast.push(Tokens::new(
-                    OpCodes::Inc(u32::try_from(counter).unwrap()),
-                    op.get_location().to_owned(),
-                ));
-            }
-            OpCodes::Dec(x) => {
-                let mut counter = *x as usize;
-                while Some(op) == p.peek().copied() {
-                    counter += 1;
+                if delta > i32::MAX as u32 {
+                    new_ast.push(Tokens::new(
+                        OpCodes::Sub(delta.wrapping_neg(), offset),
+                        op.get_location(),
+                    ));
+                } else if delta != 0 {
+                    new_ast.push(Tokens::new(OpCodes::Add(delta, offset), op.get_location()));
+                }
+            } else if let Some(mut moved) = pointer_delta(op.get_type()) {
+                while let Some(step) = p.peek().and_then(|next| pointer_delta(next.get_type())) {
+                    moved += step;
                     p.next();
                 }
-                new_ast.push(Tokens::new(
-                    OpCodes::Dec(u32::try_from(counter).unwrap()),
-                    op.get_location().to_owned(),
-                ));
-            }
-            OpCodes::Loop(x) => {
-                new_ast.push(Tokens::new(
-                    OpCodes::Loop(contract(x)),
-                    op.get_location().to_owned(),
-                ));
+                // Anything too far for a single move gets split up
+                let mut left = moved.unsigned_abs();
+                while left > 0 {
+                    let by = u32::try_from(left).unwrap_or(u32::MAX);
+                    left -= u64::from(by);
+                    if moved < 0 {
+                        new_ast.push(Tokens::new(OpCodes::Dec(by), op.get_location()));
+                    } else {
+                        new_ast.push(Tokens::new(OpCodes::Inc(by), op.get_location()));
+                    }
+                }
+            } else {
+                new_ast.push(op);
             }
-            _ => new_ast.push(op.to_owned()),
         }
+        new_ast
+    }
+}
+
+/// What a `+`/`-` does to its cell as (change, offset). Cells wrap, so the change wraps too.
+fn cell_delta(code: &OpCodes) -> Option<(u32, i32)> {
+    match code {
+        OpCodes::Add(x, offset) => Some((*x, *offset)),
+        OpCodes::Sub(x, offset) => Some((x.wrapping_neg(), *offset)),
+        _ => None,
     }
-    new_ast
 }
 
-fn clear_dead_code(ast: &[Tokens]) -> Vec<Tokens> {
-    let mut new_ast: Vec<Tokens> = vec![];
-    let mut counter = 0;
+/// How far a `>`/`<` moves the pointer.
+fn pointer_delta(code: &OpCodes) -> Option<i64> {
+    match code {
+        OpCodes::Inc(x) => Some(i64::from(*x)),
+        OpCodes::Dec(x) => Some(-i64::from(*x)),
+        _ => None,
+    }
+}
 
-    let mut p = ast.iter().peekable();
-    while let Some(part) = p.next() {
-        match part.get_type() {
-            OpCodes::Loop(x) => {
-                // Remove empty loops `[]`
-                if !x.is_empty() {
-                    new_ast.push(Tokens::new(
-                        OpCodes::Loop(x.to_vec()),
-                        part.get_location().to_owned(),
-                    ))
+struct DeadCode;
+
+impl Fold for DeadCode {
+    fn fold_block(&self, block: Vec<Tokens>, top_level: bool) -> Vec<Tokens> {
+        let mut new_ast: Vec<Tokens> = vec![];
+        let mut counter = 0;
+        // Whether the current cell has to be zero here. `[]` spins forever on anything else, so
+        // that's the only place it can go.
+        let mut zero = top_level;
+
+        let mut p = block.iter().peekable();
+        while let Some(part) = p.next() {
+            match part.get_type() {
+                OpCodes::Loop(x) => {
+                    // Remove empty loops `[]`
+                    if !x.is_empty() || !zero {
+                        new_ast.push(part.to_owned())
+                    }
+                }
+                OpCodes::Clear(offset) => {
+                    // Do we have `[+]` or `[-]` at the beginning? Only the program as a whole
+                    // starts off with every cell at zero, a loop body doesn't. Clearing a cell
+                    // that's already zero anywhere else is just as useless.
+                    if (counter != 0 || !top_level) && (*offset != 0 || !zero) {
+                        // If not, push that mf
+                        new_ast.push(part.to_owned())
+                    }
                 }
+                enummy @ (OpCodes::Add(..)
+                | OpCodes::Sub(..)
+                | OpCodes::Inc(_)
+                | OpCodes::Dec(_)) => {
+                    // Option<Tokens>       Option<OpCodes>
+                    if p.peek().copied().is_some()
+                        && p.peek().copied().unwrap().get_type()
+                            == enummy.opposite().as_ref().unwrap()
+                    {
+                        p.next();
+                    } else {
+                        new_ast.push(part.to_owned());
+                    }
+                }
+                _ => new_ast.push(part.to_owned()),
             }
-            OpCodes::Clear => {
-                // Do we have `[+]` or `[-]` at the beginning?
-                if counter != 0 {
-                    // If not, push that mf
-                    new_ast.push(Tokens::new(OpCodes::Clear, part.get_location().to_owned()))
+            zero = match part.get_type() {
+                // Nothing gets past a loop until the cell is zero
+                OpCodes::Loop(_)
+                | OpCodes::Scan(_)
+                | OpCodes::MulAdd(_)
+                | OpCodes::Clear(0)
+                | OpCodes::Set(0, 0) => true,
+                OpCodes::Add(_, offset)
+                | OpCodes::Sub(_, offset)
+                | OpCodes::Clear(offset)
+                | OpCodes::Set(_, offset)
+                | OpCodes::Input(offset) => zero && *offset != 0,
+                OpCodes::Output(_) => zero,
+                OpCodes::Inc(_) | OpCodes::Dec(_) => false,
+            };
+            counter += 1;
+        }
+        new_ast
+    }
+}
+
+struct Clear;
+
+impl Fold for Clear {
+    fn fold_token(&self, token: Tokens) -> Vec<Tokens> {
+        if let OpCodes::Loop(ref x) = token.get_type() {
+            // Do we have `[x]`
+            if let [only] = x.as_slice() {
+                // Only match on possible clear values
+                if let OpCodes::Add(_, 0) | OpCodes::Sub(_, 0) = only.get_type() {
+                    return vec![Tokens::new(OpCodes::Clear(0), token.get_location())];
                 }
             }
-            enummy @ (OpCodes::Add(_) | OpCodes::Sub(_) | OpCodes::Inc(_) | OpCodes::Dec(_)) => {
-                // Option<Tokens>       Option<OpCodes>
-                if p.peek().copied().is_some()
-                    && p.peek().copied().unwrap().get_type() == enummy.opposite().as_ref().unwrap()
-                {
-                    p.next();
-                } else {
-                    new_ast.push(Tokens::new(
-                        enummy.to_owned(),
-                        part.get_location().to_owned(),
-                    ));
+        }
+        vec![token]
+    }
+}
+
+struct Multiply;
+
+impl Fold for Multiply {
+    fn fold_token(&self, token: Tokens) -> Vec<Tokens> {
+        let code = match token.get_type() {
+            OpCodes::Loop(ref x) => match multiply_targets(x) {
+                // `[-]` with nowhere to copy to
+                Some(targets) if targets.is_empty() => OpCodes::Clear(0),
+                Some(targets) => OpCodes::MulAdd(targets),
+                None => return vec![token],
+            },
+            _ => return vec![token],
+        };
+        vec![Tokens::new(code, token.get_location())]
+    }
+}
+
+/// If a loop body only does arithmetic and pointer movement, ends up back where it started, and
+/// takes exactly one off the current cell every time round, then it just adds a multiple of the
+/// current cell to its other cells. Return those (offset, factor) pairs in the order they show up.
+fn multiply_targets(body: &[Tokens]) -> Option<Vec<(i32, i32)>> {
+    let mut position: i32 = 0;
+    let mut deltas: Vec<(i32, i32)> = vec![];
+    for part in body {
+        let (cell, delta) = match part.get_type() {
+            OpCodes::Inc(x) => {
+                position = position.checked_add(i32::try_from(*x).ok()?)?;
+                continue;
+            }
+            OpCodes::Dec(x) => {
+                position = position.checked_sub(i32::try_from(*x).ok()?)?;
+                continue;
+            }
+            OpCodes::Add(x, offset) => (position.checked_add(*offset)?, *x as i32),
+            OpCodes::Sub(x, offset) => (position.checked_add(*offset)?, (*x as i32).wrapping_neg()),
+            _ => return None,
+        };
+        match deltas.iter_mut().find(|(offset, _)| *offset == cell) {
+            Some((_, factor)) => *factor = factor.wrapping_add(delta),
+            None => deltas.push((cell, delta)),
+        }
+    }
+    if position != 0 {
+        return None;
+    }
+    match deltas.iter().position(|(offset, _)| *offset == 0) {
+        Some(index) if deltas[index].1 == -1 => {
+            deltas.remove(index);
+        }
+        _ => return None,
+    }
+    deltas.retain(|(_, factor)| *factor != 0);
+    Some(deltas)
+}
+
+/// Turn `[>]`, `[<<]` and friends into a single search for the next zero cell.
+struct Scan;
+
+impl Fold for Scan {
+    fn fold_token(&self, token: Tokens) -> Vec<Tokens> {
+        let stride = match token.get_type() {
+            OpCodes::Loop(ref x) => match x.as_slice() {
+                [only] => match only.get_type() {
+                    OpCodes::Inc(by) => i32::try_from(*by).ok(),
+                    OpCodes::Dec(by) => i32::try_from(*by).ok().map(|by| -by),
+                    _ => None,
+                },
+                _ => None,
+            },
+            _ => None,
+        };
+        match stride {
+            Some(stride) => vec![Tokens::new(OpCodes::Scan(stride), token.get_location())],
+            None => vec![token],
+        }
+    }
+}
+
+/// Fuse `[-]` and the additions or subtractions right after it into one `OpCodes::Set`.
+struct Set;
+
+impl Fold for Set {
+    fn fold_block(&self, block: Vec<Tokens>, _top_level: bool) -> Vec<Tokens> {
+        let mut new_ast: Vec<Tokens> = vec![];
+        for part in block {
+            let fused = match (new_ast.last().map(Tokens::get_type), part.get_type()) {
+                (Some(OpCodes::Clear(cell)), OpCodes::Add(x, offset)) if cell == offset => {
+                    Some(OpCodes::Set(*x, *cell))
+                }
+                (Some(OpCodes::Clear(cell)), OpCodes::Sub(x, offset)) if cell == offset => {
+                    Some(OpCodes::Set(x.wrapping_neg(), *cell))
                 }
+                (Some(OpCodes::Set(value, cell)), OpCodes::Add(x, offset)) if cell == offset => {
+                    Some(OpCodes::Set(value.wrapping_add(*x), *cell))
+                }
+                (Some(OpCodes::Set(value, cell)), OpCodes::Sub(x, offset)) if cell == offset => {
+                    Some(OpCodes::Set(value.wrapping_sub(*x), *cell))
+                }
+                _ => None,
+            };
+            match fused {
+                Some(code) => {
+                    // Keep pointing at the `[-]` that started it
+                    let start = new_ast.pop().unwrap().get_location();
+                    new_ast.push(Tokens::new(code, start));
+                }
+                None => new_ast.push(part),
             }
-            default => new_ast.push(Tokens::new(
-                default.to_owned(),
-                part.get_location().to_owned(),
-            )),
-        }
-        counter += 1;
-    }
-    new_ast
-}
-
-fn clear(ast: &[Tokens]) -> Vec<Tokens> {
-    let mut new_ast: Vec<Tokens> = vec![];
-    for part in ast {
-        match part.get_type() {
-            OpCodes::Loop(ref x) => {
-                // Do we have `[x]`
-                if x.len() == 1 {
-                    match x.first().unwrap().get_type() {
-                        // Only match on possible clear values
-                        OpCodes::Add(_) | OpCodes::Sub(_) => new_ast
-                            .push(Tokens::new(OpCodes::Clear, part.get_location().to_owned())),
-                        _ => new_ast.push(part.clone()),
+        }
+        new_ast
+    }
+}
+
+/// Drop writes to a cell that get overwritten by `,` before anything reads the cell. With
+/// `EofPolicy::Unchanged` the cell keeps whatever the last write left there once the input runs
+/// out, so `,` counts as reading it instead.
+struct IoClobber {
+    eof: EofPolicy,
+}
+
+impl Fold for IoClobber {
+    fn fold_block(&self, block: Vec<Tokens>, _top_level: bool) -> Vec<Tokens> {
+        let mut dead: Vec<usize> = vec![];
+        // Writes nothing has looked at yet, as (cell relative to the start of the block, index)
+        let mut unread: Vec<(i32, usize)> = vec![];
+        let mut position: i32 = 0;
+        for (index, part) in block.iter().enumerate() {
+            match part.get_type() {
+                OpCodes::Inc(x) => position = position.wrapping_add(*x as i32),
+                OpCodes::Dec(x) => position = position.wrapping_sub(*x as i32),
+                OpCodes::Add(_, offset)
+                | OpCodes::Sub(_, offset)
+                | OpCodes::Clear(offset)
+                | OpCodes::Set(_, offset) => unread.push((position + offset, index)),
+                OpCodes::Input(offset) => unread.retain(|(cell, index)| {
+                    if *cell == position + offset && self.eof != EofPolicy::Unchanged {
+                        dead.push(*index);
                     }
-                } else {
-                    new_ast.push(Tokens::new(
-                        OpCodes::Loop(clear(x)),
-                        part.get_location().to_owned(),
-                    ));
-                }
+                    *cell != position + offset
+                }),
+                OpCodes::Output(offset) => unread.retain(|(cell, _)| *cell != position + offset),
+                OpCodes::MulAdd(targets) => unread.retain(|(cell, _)| {
+                    *cell != position
+                        && targets.iter().all(|(offset, _)| *cell != position + offset)
+                }),
+                // The loop test reads the cell and we have no idea what the body gets up to
+                _ => unread.clear(),
             }
-            _ => new_ast.push(part.to_owned()),
         }
+        block
+            .into_iter()
+            .enumerate()
+            .filter(|(index, _)| !dead.contains(index))
+            .map(|(_, part)| part)
+            .collect()
     }
-    new_ast
 }
 
-fn remove_pure(ast: &[Tokens]) -> Vec<Tokens> {
-    let mut new_ast: Vec<Tokens> = ast.to_owned();
-    let mut pure_ast: Vec<Tokens> = vec![];
-    while let Some(op) = new_ast.pop() {
-        match op.get_type() {
-            // Basically if we have codes at the end that cause side effects, we push that, but if
-            // we don't, we push that to pure_ast instead. Later I might add a warning message
-            // about the no-effect code.
-            OpCodes::Input | OpCodes::Output | OpCodes::Loop { .. } => {
-                new_ast.push(op);
-                break;
+/// Instead of moving the pointer before every cell operation, fold the movement into the offset of
+/// each operation and only actually move it where something needs the real pointer: before a loop
+/// (or anything else that can't take an offset), and at the end of the block.
+struct Offsets;
+
+impl Fold for Offsets {
+    fn fold_block(&self, block: Vec<Tokens>, _top_level: bool) -> Vec<Tokens> {
+        let mut new_ast: Vec<Tokens> = vec![];
+        // How far the pointer should have moved by now, and where that movement started
+        let mut moved: Option<(i32, (u32, u32))> = None;
+        for part in block {
+            let shift = moved.map_or(0, |(by, _)| by);
+            let start = moved.map_or(part.get_location(), |(_, at)| at);
+            // `None` for anything that needs the real pointer, which includes any move or offset
+            // that would overflow an `i32` with the movement folded in
+            let code = match part.get_type() {
+                OpCodes::Inc(x) => {
+                    match i32::try_from(*x).ok().and_then(|x| shift.checked_add(x)) {
+                        Some(by) => {
+                            moved = Some((by, start));
+                            continue;
+                        }
+                        None => None,
+                    }
+                }
+                OpCodes::Dec(x) => {
+                    match i32::try_from(*x).ok().and_then(|x| shift.checked_sub(x)) {
+                        Some(by) => {
+                            moved = Some((by, start));
+                            continue;
+                        }
+                        None => None,
+                    }
+                }
+                OpCodes::Add(x, offset) => offset.checked_add(shift).map(|at| OpCodes::Add(*x, at)),
+                OpCodes::Sub(x, offset) => offset.checked_add(shift).map(|at| OpCodes::Sub(*x, at)),
+                OpCodes::Clear(offset) => offset.checked_add(shift).map(OpCodes::Clear),
+                OpCodes::Set(x, offset) => offset.checked_add(shift).map(|at| OpCodes::Set(*x, at)),
+                OpCodes::Output(offset) => offset.checked_add(shift).map(OpCodes::Output),
+                OpCodes::Input(offset) => offset.checked_add(shift).map(OpCodes::Input),
+                _ => None,
+            };
+            let code = code.unwrap_or_else(|| {
+                flush_moves(&mut new_ast, moved.take());
+                part.get_type().to_owned()
+            });
+            new_ast.push(Tokens::new(code, part.get_location()));
+        }
+        flush_moves(&mut new_ast, moved.take());
+        new_ast
+    }
+}
+
+fn flush_moves(ast: &mut Vec<Tokens>, moved: Option<(i32, (u32, u32))>) {
+    if let Some((by, location)) = moved {
+        if by != 0 {
+            ast.push(Tokens::new(OpCodes::moves(by), location));
+        }
+    }
+}
+
+struct RemovePure;
+
+impl Fold for RemovePure {
+    fn fold_block(&self, block: Vec<Tokens>, top_level: bool) -> Vec<Tokens> {
+        // Whatever a loop body leaves behind can still be looked at on the next time round
+        if !top_level {
+            return block;
+        }
+        let mut new_ast: Vec<Tokens> = block;
+        let mut pure_ast: Vec<Tokens> = vec![];
+        while let Some(op) = new_ast.pop() {
+            match op.get_type() {
+                // Basically if we have codes at the end that cause side effects, we push that, but
+                // if we don't, we push that to pure_ast instead. Later I might add a warning
+                // message about the no-effect code.
+                OpCodes::Input(_)
+                | OpCodes::Output(_)
+                | OpCodes::Loop { .. }
+                | OpCodes::Scan(_) => {
+                    new_ast.push(op);
+                    break;
+                }
+                _ => pure_ast.push(op),
             }
-            _ => pure_ast.push(op),
         }
+        new_ast
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::ast::generate_ast;
+
+    fn run(program: &str, passes: &[OptimizerStrategies]) -> Vec<OpCodes> {
+        run_with_eof(program, passes, EofPolicy::Zero)
+    }
+
+    /// Run `passes` over `program` and throw away the locations, they just get in the way here.
+    fn run_with_eof(program: &str, passes: &[OptimizerStrategies], eof: EofPolicy) -> Vec<OpCodes> {
+        fn strip(ast: &[Tokens]) -> Vec<OpCodes> {
+            ast.iter()
+                .map(|part| match part.get_type() {
+                    OpCodes::Loop(x) => OpCodes::Loop(
+                        strip(x)
+                            .into_iter()
+                            .map(|code| Tokens::new(code, (0, 0)))
+                            .collect(),
+                    ),
+                    other => other.to_owned(),
+                })
+                .collect()
+        }
+        strip(&optimize(
+            &generate_ast(&mut program.chars()).unwrap(),
+            passes,
+            eof,
+        ))
+    }
+
+    #[test]
+    fn multiply_loop() {
+        use OptimizerStrategies::*;
+        assert_eq!(
+            run("[->>+++<<]", &[MultiplyLoop]),
+            [OpCodes::MulAdd(vec![(2, 3)])]
+        );
+        assert_eq!(
+            run("[>+<-<<++>>]", &[MultiplyLoop]),
+            [OpCodes::MulAdd(vec![(1, 1), (-2, 2)])]
+        );
+        // Adds nothing anywhere else, so it's just a clear
+        assert_eq!(run("[->+-<]", &[MultiplyLoop]), [OpCodes::Clear(0)]);
+        // Doesn't end up where it started, or doesn't count the current cell down by one
+        assert!(matches!(
+            run("[->+<<]", &[MultiplyLoop])[..],
+            [OpCodes::Loop(_)]
+        ));
+        assert!(matches!(
+            run("[-->+<]", &[MultiplyLoop])[..],
+            [OpCodes::Loop(_)]
+        ));
+        assert!(matches!(
+            run("[->.<]", &[MultiplyLoop])[..],
+            [OpCodes::Loop(_)]
+        ));
+    }
+
+    #[test]
+    fn offsets() {
+        use OptimizerStrategies::*;
+        assert_eq!(
+            run(">+>+>+<<<", &[Contractions, Offsets]),
+            [OpCodes::Add(1, 1), OpCodes::Add(1, 2), OpCodes::Add(1, 3)]
+        );
+        // The pointer still has to be in the right place for the loop and at the end
+        assert_eq!(
+            run(">+>.[-]>", &[Contractions, Offsets]),
+            [
+                OpCodes::Add(1, 1),
+                OpCodes::Output(2),
+                OpCodes::Inc(2),
+                OpCodes::Loop(vec![Tokens::new(OpCodes::Sub(1, 0), (0, 0))]),
+                OpCodes::Inc(1),
+            ]
+        );
+        // Movement that won't fit in an offset gets made for real instead
+        let huge = i32::MAX as u32;
+        let ast: Vec<_> = [
+            OpCodes::Inc(huge),
+            OpCodes::Inc(1),
+            OpCodes::Add(1, 0),
+            OpCodes::Dec(1),
+            OpCodes::Output(i32::MIN),
+            OpCodes::Dec(u32::MAX),
+        ]
+        .into_iter()
+        .map(|code| Tokens::new(code, (1, 1)))
+        .collect();
+        let codes: Vec<_> = optimize(&ast, &[Offsets], EofPolicy::Zero)
+            .into_iter()
+            .map(|part| part.get_type().to_owned())
+            .collect();
+        assert_eq!(
+            codes,
+            [
+                OpCodes::Inc(huge),
+                OpCodes::Inc(1),
+                OpCodes::Add(1, 0),
+                OpCodes::Dec(1),
+                OpCodes::Output(i32::MIN),
+                OpCodes::Dec(u32::MAX),
+            ]
+        );
+    }
+
+    #[test]
+    fn io_clobber() {
+        use OptimizerStrategies::*;
+        assert_eq!(run("+++,", &[Contractions, IoClobber]), [OpCodes::Input(0)]);
+        assert_eq!(
+            run_with_eof("+++,", &[Contractions, IoClobber], EofPolicy::MinusOne),
+            [OpCodes::Input(0)]
+        );
+        // The input might run out and leave the cell as it was
+        assert_eq!(
+            run_with_eof("+++,", &[Contractions, IoClobber], EofPolicy::Unchanged),
+            [OpCodes::Add(3, 0), OpCodes::Input(0)]
+        );
+        assert_eq!(
+            run("[-]>+<,", &[ClearLoop, IoClobber]),
+            [
+                OpCodes::Inc(1),
+                OpCodes::Add(1, 0),
+                OpCodes::Dec(1),
+                OpCodes::Input(0)
+            ]
+        );
+        // Anything that reads the cell in between keeps the write alive
+        assert_eq!(
+            run("+.,", &[IoClobber]),
+            [OpCodes::Add(1, 0), OpCodes::Output(0), OpCodes::Input(0)]
+        );
+        assert_eq!(run("+[,]", &[IoClobber])[..1], [OpCodes::Add(1, 0)]);
+    }
+
+    #[test]
+    fn optimize_settles() {
+        let programs = [
+            "++-+-+",
+            ">+>+>+<<<",
+            "[->>+++<<]",
+            "+++,",
+            "+[->+<]>[>]<<[-]++.,[-<+>]",
+        ];
+        for program in programs {
+            let ast = generate_ast(&mut program.chars()).unwrap();
+            let (once, report) = optimize_with_statistics(&ast, &level(MAX_LEVEL), EofPolicy::Zero);
+            assert!(report.settled, "{program} didn't settle");
+            let twice = optimize(&once, &level(MAX_LEVEL), EofPolicy::Zero);
+            assert_eq!(once, twice, "{program} changed on a second run");
+        }
+    }
+
+    #[test]
+    fn contractions() {
+        use OptimizerStrategies::*;
+        assert_eq!(run("++-+-+", &[Contractions]), [OpCodes::Add(2, 0)]);
+        assert_eq!(run("+---", &[Contractions]), [OpCodes::Sub(2, 0)]);
+        assert_eq!(run(">><<<", &[Contractions]), [OpCodes::Dec(1)]);
+        assert_eq!(run("+-><.", &[Contractions]), [OpCodes::Output(0)]);
+        assert_eq!(
+            run("[++>-<+]", &[Contractions]),
+            [OpCodes::Loop(vec![
+                Tokens::new(OpCodes::Add(2, 0), (0, 0)),
+                Tokens::new(OpCodes::Inc(1), (0, 0)),
+                Tokens::new(OpCodes::Sub(1, 0), (0, 0)),
+                Tokens::new(OpCodes::Dec(1), (0, 0)),
+                Tokens::new(OpCodes::Add(1, 0), (0, 0)),
+            ])]
+        );
+    }
+
+    #[test]
+    fn scan_loop() {
+        use OptimizerStrategies::*;
+        assert_eq!(run("[>]", &[ScanLoop]), [OpCodes::Scan(1)]);
+        assert_eq!(run("[<<<]", &[Contractions, ScanLoop]), [OpCodes::Scan(-3)]);
+        assert!(matches!(run("[>+]", &[ScanLoop])[..], [OpCodes::Loop(_)]));
+    }
+
+    #[test]
+    fn set_value() {
+        use OptimizerStrategies::*;
+        assert_eq!(
+            run(".[-]+++", &[Contractions, ClearLoop, SetValue]),
+            [OpCodes::Output(0), OpCodes::Set(3, 0)]
+        );
+        assert_eq!(
+            run(".[-]--", &[Contractions, ClearLoop, SetValue]),
+            [OpCodes::Output(0), OpCodes::Set(2u32.wrapping_neg(), 0)]
+        );
+        // Only additions to the cell that got cleared
+        assert_eq!(
+            run(".[-]>+", &[ClearLoop, SetValue]),
+            [
+                OpCodes::Output(0),
+                OpCodes::Clear(0),
+                OpCodes::Inc(1),
+                OpCodes::Add(1, 0)
+            ]
+        );
+    }
+
+    #[test]
+    fn dead_code() {
+        use OptimizerStrategies::*;
+        // Every cell starts off at zero, and `[]` can only be skipped on zero
+        assert_eq!(run("[]+", &[DeadCode]), [OpCodes::Add(1, 0)]);
+        assert_eq!(run("[-]+", &[ClearLoop, DeadCode]), [OpCodes::Add(1, 0)]);
+        assert_eq!(
+            run("+[[-][]]", &[DeadCode]),
+            [
+                OpCodes::Add(1, 0),
+                OpCodes::Loop(vec![Tokens::new(
+                    OpCodes::Loop(vec![Tokens::new(OpCodes::Sub(1, 0), (0, 0))]),
+                    (0, 0)
+                )])
+            ]
+        );
+        // Here it hangs, which is the program's business
+        assert_eq!(
+            run("+[]", &[DeadCode]),
+            [OpCodes::Add(1, 0), OpCodes::Loop(vec![])]
+        );
+        assert_eq!(
+            run("+[>[]]", &[DeadCode])[1],
+            OpCodes::Loop(vec![
+                Tokens::new(OpCodes::Inc(1), (0, 0)),
+                Tokens::new(OpCodes::Loop(vec![]), (0, 0))
+            ])
+        );
+    }
+
+    #[test]
+    fn clear_loop() {
+        use OptimizerStrategies::*;
+        assert_eq!(
+            run(".[-]", &[ClearLoop]),
+            [OpCodes::Output(0), OpCodes::Clear(0)]
+        );
+        assert_eq!(
+            run("+[[+]>]", &[ClearLoop]),
+            [
+                OpCodes::Add(1, 0),
+                OpCodes::Loop(vec![
+                    Tokens::new(OpCodes::Clear(0), (0, 0)),
+                    Tokens::new(OpCodes::Inc(1), (0, 0))
+                ])
+            ]
+        );
+    }
+
+    #[test]
+    fn pure_code() {
+        use OptimizerStrategies::*;
+        assert_eq!(run(".+>", &[PureCode]), [OpCodes::Output(0)]);
+        // The next time round the loop can still see what the body did
+        assert_eq!(
+            run("+[.->]", &[PureCode])[1],
+            OpCodes::Loop(vec![
+                Tokens::new(OpCodes::Output(0), (0, 0)),
+                Tokens::new(OpCodes::Sub(1, 0), (0, 0)),
+                Tokens::new(OpCodes::Inc(1), (0, 0))
+            ])
+        );
     }
-    new_ast
 }